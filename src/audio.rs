@@ -0,0 +1,520 @@
+use std::{fs::File, io::ErrorKind, path::Path, time::Duration};
+
+use color_eyre::eyre::eyre;
+use lofty::{
+    config::WriteOptions,
+    file::{AudioFile, TaggedFileExt},
+    probe::Probe,
+    tag::{items::Timestamp, Accessor, ItemKey, Tag, TagType},
+};
+use symphonia::core::{
+    errors::Error as SymphoniaError,
+    formats::{FormatOptions, FormatReader},
+    io::MediaSourceStream,
+    meta::MetadataOptions,
+    probe::Hint,
+    units::TimeBase,
+};
+
+use crate::{MyRes, GL_FILENAME_PATTERN, GL_MUSICDIR};
+
+// Alles, was beim Scannen als Song erkannt wird.
+pub const GL_AUDIO_EXTENSIONS: &[&str] = &[
+    "mp3", "flac", "ogg", "oga", "opus", "m4a", "mp4", "aac", "wav",
+];
+
+pub fn get_extension(path: &Path) -> String {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_lowercase()
+}
+
+pub fn is_audio_file(path: &Path) -> bool {
+    GL_AUDIO_EXTENSIONS.contains(&get_extension(path).as_str())
+}
+
+pub fn get_mime_type(path: &Path) -> &'static str {
+    match get_extension(path).as_str() {
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "ogg" | "oga" => "audio/ogg",
+        "opus" => "audio/ogg; codecs=opus",
+        "m4a" | "mp4" => "audio/mp4",
+        "aac" => "audio/aac",
+        "wav" => "audio/wav",
+        _ => "application/octet-stream",
+    }
+}
+
+#[derive(Default, Debug, PartialEq)]
+pub struct SongTags {
+    pub songname: String,
+    pub artist: String,
+    pub album: String,
+    pub album_artist: String,
+    pub track_number: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub year: Option<u32>,
+    pub genre: String,
+    pub composer: String,
+    pub comment: String,
+    // Woher die Werte stammen, z.B. "id3v2" oder "id3v1+filename".
+    pub metadata_source: String,
+}
+
+impl SongTags {
+    // Füllt alle leeren Felder aus other auf. Gibt zurück, ob dabei etwas übernommen wurde.
+    fn fill_from(&mut self, other: SongTags) -> bool {
+        fn text(target: &mut String, value: String) -> bool {
+            let fill = target.is_empty() && !value.is_empty();
+            if fill {
+                *target = value;
+            }
+            fill
+        }
+        fn number(target: &mut Option<u32>, value: Option<u32>) -> bool {
+            let fill = target.is_none() && value.is_some();
+            if fill {
+                *target = value;
+            }
+            fill
+        }
+        [
+            text(&mut self.songname, other.songname),
+            text(&mut self.artist, other.artist),
+            text(&mut self.album, other.album),
+            text(&mut self.album_artist, other.album_artist),
+            number(&mut self.track_number, other.track_number),
+            number(&mut self.disc_number, other.disc_number),
+            number(&mut self.year, other.year),
+            text(&mut self.genre, other.genre),
+            text(&mut self.composer, other.composer),
+            text(&mut self.comment, other.comment),
+        ]
+        .contains(&true)
+    }
+
+    fn is_complete(&self) -> bool {
+        !self.songname.is_empty() && !self.artist.is_empty() && !self.album.is_empty()
+    }
+}
+
+// Reihenfolge, in der die Tag-Formate einer Datei abgefragt werden. ID3v1 kommt
+// nach APE, weil die Felder dort auf 30 Zeichen gekürzt sind.
+const GL_TAG_PRIORITY: &[(TagType, &str)] = &[
+    (TagType::Id3v2, "id3v2"),
+    (TagType::VorbisComments, "vorbis"),
+    (TagType::Mp4Ilst, "mp4"),
+    (TagType::Ape, "ape"),
+    (TagType::Id3v1, "id3v1"),
+    (TagType::RiffInfo, "riff"),
+    (TagType::AiffText, "aiff"),
+];
+
+fn tags_from_tag(tag: &Tag) -> SongTags {
+    let text = |key: ItemKey| tag.get_string(key).unwrap_or_default().trim().to_owned();
+    SongTags {
+        songname: tag.title().unwrap_or_default().trim().to_owned(),
+        artist: tag.artist().unwrap_or_default().trim().to_owned(),
+        album: tag.album().unwrap_or_default().trim().to_owned(),
+        album_artist: text(ItemKey::AlbumArtist),
+        track_number: tag.track(),
+        disc_number: tag.disk(),
+        year: tag.date().map(|d| d.year as u32).or_else(|| {
+            // Manche Tags haben nur ein freies Jahr-Feld, z.B. "1999" oder "1999-05-01".
+            tag.get_string(ItemKey::Year)
+                .and_then(|y| y.get(..4))
+                .and_then(|y| y.parse().ok())
+        }),
+        genre: tag.genre().unwrap_or_default().trim().to_owned(),
+        composer: text(ItemKey::Composer),
+        comment: tag.comment().unwrap_or_default().trim().to_owned(),
+        metadata_source: String::new(),
+    }
+}
+
+// Liest die Tags in der Reihenfolge von GL_TAG_PRIORITY und füllt fehlende Felder
+// mit dem nächsten Format auf. Was danach noch fehlt, kommt aus dem Dateinamen.
+pub fn read_tags(path: &Path) -> SongTags {
+    let mut tags = SongTags::default();
+    let mut sources = vec![];
+
+    match Probe::open(path).and_then(|p| p.guess_file_type()?.read()) {
+        Ok(file) => {
+            for (tag_type, name) in GL_TAG_PRIORITY {
+                if tags.is_complete() {
+                    break;
+                }
+                if let Some(tag) = file.tag(*tag_type) {
+                    if tags.fill_from(tags_from_tag(tag)) {
+                        sources.push(*name);
+                    }
+                }
+            }
+        }
+        Err(e) => println!("read_tags: {}: {e}", path.display()),
+    }
+
+    if !tags.is_complete() {
+        let relative = path.strip_prefix(&*GL_MUSICDIR).unwrap_or(path);
+        if tags.fill_from(parse_filename(&GL_FILENAME_PATTERN, relative)) {
+            sources.push("filename");
+        }
+    }
+
+    tags.metadata_source = if sources.is_empty() {
+        "none".to_string()
+    } else {
+        sources.join("+")
+    };
+    tags
+}
+
+// Schreibt die Werte in das primäre Tag-Format der Datei (ID3v2, Vorbis, MP4, ...).
+// Leere Felder werden aus dem Tag entfernt.
+pub fn write_tags(path: &Path, tags: &SongTags) -> MyRes<()> {
+    println!("write_tags({})", path.display());
+    let mut file = Probe::open(path)?.guess_file_type()?.read()?;
+    let tag_type = file.primary_tag_type();
+    if file.tag(tag_type).is_none() {
+        file.insert_tag(Tag::new(tag_type));
+    }
+    let tag = file
+        .tag_mut(tag_type)
+        .ok_or_else(|| eyre!("could not create {tag_type:?} tag"))?;
+
+    fn set(tag: &mut Tag, key: ItemKey, value: &str) {
+        if value.is_empty() {
+            tag.remove_key(key);
+        } else {
+            tag.insert_text(key, value.to_owned());
+        }
+    }
+    set(tag, ItemKey::TrackTitle, &tags.songname);
+    set(tag, ItemKey::TrackArtist, &tags.artist);
+    set(tag, ItemKey::AlbumTitle, &tags.album);
+    set(tag, ItemKey::AlbumArtist, &tags.album_artist);
+    set(tag, ItemKey::Genre, &tags.genre);
+    set(tag, ItemKey::Composer, &tags.composer);
+    set(tag, ItemKey::Comment, &tags.comment);
+    match tags.track_number {
+        Some(t) => tag.set_track(t),
+        None => tag.remove_track(),
+    }
+    match tags.disc_number {
+        Some(d) => tag.set_disk(d),
+        None => tag.remove_disk(),
+    }
+    match tags.year {
+        Some(y) => tag.set_date(Timestamp {
+            year: y as u16,
+            ..Default::default()
+        }),
+        None => tag.remove_date(),
+    }
+
+    file.save_to_path(path, WriteOptions::default())?;
+    Ok(())
+}
+
+enum PatternToken<'a> {
+    Literal(&'a str),
+    Field(&'a str),
+}
+
+const GL_PATTERN_FIELDS: [&str; 8] = [
+    "title",
+    "artist",
+    "album",
+    "album_artist",
+    "genre",
+    "track",
+    "disc",
+    "year",
+];
+
+fn tokenize(segment: &str) -> Result<Vec<PatternToken<'_>>, String> {
+    let mut tokens = vec![];
+    let mut rest = segment;
+    while !rest.is_empty() {
+        match rest.find('{') {
+            Some(0) => {
+                let end = rest
+                    .find('}')
+                    .ok_or_else(|| format!("unclosed '{{' in \"{segment}\""))?;
+                let name = &rest[1..end];
+                if !GL_PATTERN_FIELDS.contains(&name) {
+                    return Err(format!(
+                        "unknown field {{{name}}} in \"{segment}\", known fields: {}",
+                        GL_PATTERN_FIELDS.join(", ")
+                    ));
+                }
+                tokens.push(PatternToken::Field(name));
+                rest = &rest[end + 1..];
+            }
+            Some(i) => {
+                tokens.push(PatternToken::Literal(&rest[..i]));
+                rest = &rest[i..];
+            }
+            None => {
+                if rest.contains('}') {
+                    return Err(format!("'}}' without '{{' in \"{segment}\""));
+                }
+                tokens.push(PatternToken::Literal(rest));
+                rest = "";
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+// Wird beim Start aufgerufen, damit ein kaputtes FILENAME_PATTERN nicht erst im Scan auffällt.
+pub fn check_filename_pattern(pattern: &str) -> MyRes<()> {
+    for segment in pattern.split('/') {
+        tokenize(segment).map_err(|e| eyre!("FILENAME_PATTERN: {e}"))?;
+    }
+    Ok(())
+}
+
+// Passt ein Segment des Musters (z.B. "{track} - {title}") auf einen Ordner- oder
+// Dateinamen. Gibt None zurück, wenn das Segment nicht passt.
+fn match_segment(segment: &str, text: &str, tags: &mut SongTags) -> Option<()> {
+    let mut found = SongTags::default();
+    let tokens = tokenize(segment).ok()?;
+    let mut rest = text;
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            PatternToken::Literal(l) => rest = rest.strip_prefix(l)?,
+            PatternToken::Field(name) => {
+                let value = match tokens.get(i + 1) {
+                    Some(PatternToken::Literal(next)) => {
+                        let end = rest.find(next)?;
+                        let value = &rest[..end];
+                        rest = &rest[end..];
+                        value
+                    }
+                    _ => std::mem::take(&mut rest),
+                }
+                .trim();
+                if value.is_empty() {
+                    return None;
+                }
+                let number = || value.parse::<u32>().ok();
+                match *name {
+                    "title" => found.songname = value.to_owned(),
+                    "artist" => found.artist = value.to_owned(),
+                    "album" => found.album = value.to_owned(),
+                    "album_artist" => found.album_artist = value.to_owned(),
+                    "genre" => found.genre = value.to_owned(),
+                    "track" => found.track_number = Some(number()?),
+                    "disc" => found.disc_number = Some(number()?),
+                    "year" => found.year = Some(number()?),
+                    _ => {}
+                }
+            }
+        }
+    }
+    if !rest.is_empty() {
+        return None;
+    }
+    tags.fill_from(found);
+    Some(())
+}
+
+// Muster und Pfad werden von hinten verglichen: das letzte Segment des Musters
+// gehört zum Dateinamen, das vorletzte zum Ordner darüber usw.
+pub fn parse_filename(pattern: &str, relative_path: &Path) -> SongTags {
+    let mut tags = SongTags::default();
+    let stem = relative_path
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+    let mut parts = relative_path
+        .parent()
+        .map(|p| {
+            p.iter()
+                .map(|c| c.to_string_lossy().to_string())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    parts.push(stem.clone());
+
+    for (segment, part) in pattern.rsplit('/').zip(parts.iter().rev()) {
+        let _ = match_segment(segment, part, &mut tags);
+    }
+
+    // Passt der Dateiname nicht aufs Muster, ist er immer noch der beste Titel.
+    if tags.songname.is_empty() {
+        tags.songname = stem;
+    }
+    tags
+}
+
+// Exakte Länge eines Songs. Jede Quelle wird nacheinander probiert, der Fehler
+// der letzten wird zurückgegeben, statt still auf 0 zu fallen.
+pub fn get_songlength(path: &Path) -> MyRes<Duration> {
+    let mut errors = vec![];
+    // mp3_duration zählt die Frames durch und ist damit auch bei VBR genau.
+    if get_extension(path) == "mp3" {
+        match mp3_duration::from_path(path) {
+            Ok(d) if !d.is_zero() => return Ok(d),
+            Ok(_) => errors.push("mp3_duration: zero length".to_string()),
+            Err(e) => errors.push(format!("mp3_duration: {e}")),
+        }
+    }
+    // Andere Formate haben die Länge im Header.
+    match Probe::open(path)
+        .and_then(|p| p.guess_file_type().map_err(Into::into))
+        .and_then(|p| p.read())
+    {
+        Ok(f) if !f.properties().duration().is_zero() => return Ok(f.properties().duration()),
+        Ok(_) => errors.push("lofty: zero length".to_string()),
+        Err(e) => errors.push(format!("lofty: {e}")),
+    }
+    match packet_duration(path) {
+        Ok(d) if !d.is_zero() => Ok(d),
+        Ok(_) => Err(eyre!("{}; symphonia: zero length", errors.join("; ")).into()),
+        Err(e) => Err(eyre!("{}; symphonia: {e}", errors.join("; ")).into()),
+    }
+}
+
+// Letzter Ausweg: alle Pakete durchgehen und ihre Dauer aufsummieren.
+fn packet_duration(path: &Path) -> MyRes<Duration> {
+    let mut format = open_audio(path)?;
+    let track = format
+        .default_track()
+        .ok_or_else(|| eyre!("no audio track"))?;
+    let track_id = track.id;
+    let time_base = track
+        .codec_params
+        .time_base
+        .or_else(|| track.codec_params.sample_rate.map(|r| TimeBase::new(1, r)))
+        .ok_or_else(|| eyre!("no time base"))?;
+    if let Some(frames) = track.codec_params.n_frames {
+        let time = time_base.calc_time(frames);
+        return Ok(Duration::from_secs_f64(time.seconds as f64 + time.frac));
+    }
+
+    let mut end = 0;
+    loop {
+        match format.next_packet() {
+            Ok(packet) if packet.track_id() == track_id => {
+                end = end.max(packet.ts() + packet.dur());
+            }
+            Ok(_) => {}
+            Err(SymphoniaError::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => Err(e)?,
+        }
+    }
+    let time = time_base.calc_time(end);
+    Ok(Duration::from_secs_f64(time.seconds as f64 + time.frac))
+}
+
+pub fn open_audio(path: &Path) -> MyRes<Box<dyn FormatReader>> {
+    let file = File::open(path)?;
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    hint.with_extension(&get_extension(path));
+    let probed = symphonia::default::get_probe().format(
+        &hint,
+        mss,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
+    Ok(probed.format)
+}
+
+// Hash über die reinen Audio-Pakete ohne Tags. Bleibt gleich, wenn die Datei
+// verschoben, umbenannt oder neu getaggt wird.
+pub fn get_fingerprint(path: &Path) -> MyRes<String> {
+    let mut format = open_audio(path)?;
+    let track = format
+        .default_track()
+        .ok_or_else(|| eyre!("no audio track"))?
+        .id;
+
+    let mut hasher = blake3::Hasher::new();
+    loop {
+        match format.next_packet() {
+            Ok(packet) if packet.track_id() == track => {
+                hasher.update(&packet.data);
+            }
+            Ok(_) => {}
+            Err(SymphoniaError::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => Err(e)?,
+        }
+    }
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{check_filename_pattern, get_songlength, parse_filename, SongTags};
+    use crate::GL_MUSICDIR;
+
+    #[test]
+    fn test_parse_filename() {
+        let pattern = "{artist}/{album}/{track} - {title}";
+
+        let tags = parse_filename(
+            pattern,
+            Path::new("Daft Punk/Discovery/03 - Digital Love.mp3"),
+        );
+        assert_eq!(
+            tags,
+            SongTags {
+                songname: "Digital Love".to_string(),
+                artist: "Daft Punk".to_string(),
+                album: "Discovery".to_string(),
+                track_number: Some(3),
+                ..Default::default()
+            }
+        );
+
+        // Dateiname passt nicht, die Ordner aber schon.
+        let tags = parse_filename(pattern, Path::new("Daft Punk/Discovery/Digital Love.flac"));
+        assert_eq!(tags.songname, "Digital Love");
+        assert_eq!(tags.artist, "Daft Punk");
+        assert_eq!(tags.track_number, None);
+
+        // Kein Ordner, Track ist keine Zahl.
+        let tags = parse_filename(pattern, Path::new("Intro - Outro.mp3"));
+        assert_eq!(tags.songname, "Intro - Outro");
+        assert_eq!(tags.artist, "");
+
+        let tags = parse_filename(
+            "{artist} - {album} ({year})/{disc}-{track} {title}",
+            Path::new("jazz/Miles Davis - Kind of Blue (1959)/1-02 Freddie Freeloader.mp3"),
+        );
+        assert_eq!(tags.artist, "Miles Davis");
+        assert_eq!(tags.album, "Kind of Blue");
+        assert_eq!(tags.year, Some(1959));
+        assert_eq!(tags.disc_number, Some(1));
+        assert_eq!(tags.track_number, Some(2));
+        assert_eq!(tags.songname, "Freddie Freeloader");
+    }
+
+    #[test]
+    fn test_check_filename_pattern() {
+        assert!(check_filename_pattern("{artist}/{album}/{track} - {title}").is_ok());
+        assert!(check_filename_pattern("{").is_err());
+        assert!(check_filename_pattern("{artist}/{title").is_err());
+        assert!(check_filename_pattern("{artist}/{titel}").is_err());
+        assert!(check_filename_pattern("{artist}/title}").is_err());
+    }
+
+    #[test]
+    fn test_get_songlength() {
+        let ms = get_songlength(&GL_MUSICDIR.join("titanium-170190.mp3"))
+            .unwrap()
+            .as_millis();
+        assert_eq!(ms / 1000, 106);
+
+        // Kein Audio: Fehler statt 0.
+        assert!(get_songlength(Path::new("Cargo.toml")).is_err());
+    }
+}
@@ -0,0 +1,175 @@
+use std::{
+    fs,
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+
+use actix_files::NamedFile;
+use actix_web::{
+    get,
+    http::header::{HeaderValue, CACHE_CONTROL},
+    web, HttpRequest, HttpResponse,
+};
+use image::{imageops::FilterType, ImageFormat};
+use lofty::{file::TaggedFileExt, picture::PictureType, probe::Probe};
+use serde::Deserialize;
+
+use crate::{audio::SongTags, db::db_select, update_manager::db_update, MyRes, GL_CACHEDIR};
+
+const GL_COVER_MAX_SIZE: u32 = 1200;
+const GL_COVER_THUMB_SIZE: u32 = 200;
+const GL_COVER_FOLDER_FILES: &[&str] = &[
+    "cover.jpg",
+    "cover.jpeg",
+    "cover.png",
+    "folder.jpg",
+    "folder.jpeg",
+    "folder.png",
+    "front.jpg",
+    "front.png",
+    "album.jpg",
+    "album.png",
+];
+
+fn cover_dir() -> PathBuf {
+    GL_CACHEDIR.join("covers")
+}
+
+pub fn cover_file(key: &str, thumb: bool) -> PathBuf {
+    if thumb {
+        cover_dir().join(format!("{key}_thumb.jpg"))
+    } else {
+        cover_dir().join(format!("{key}.jpg"))
+    }
+}
+
+// Alle Songs eines Albums teilen sich ein Cover. Ohne Album gilt der Ordner.
+pub fn get_cover_key(path: &Path, tags: &SongTags) -> String {
+    let source = if tags.album.is_empty() {
+        format!("dir|{}", path.parent().unwrap_or(path).display())
+    } else {
+        let artist = if tags.album_artist.is_empty() {
+            &tags.artist
+        } else {
+            &tags.album_artist
+        };
+        format!(
+            "album|{}|{}",
+            artist.to_lowercase(),
+            tags.album.to_lowercase()
+        )
+    };
+    blake3::hash(source.as_bytes()).to_hex()[..32].to_string()
+}
+
+// Sorgt dafür, dass das Cover für key im Cache liegt, und gibt key zurück, wenn es
+// eins gibt. Ist der Cache älter als die Audiodatei, wird neu extrahiert.
+pub fn cache_cover(path: &Path, key: &str) -> Option<String> {
+    let cached = cover_file(key, false);
+    let modified = |p: &Path| p.metadata().and_then(|m| m.modified()).ok();
+    if cached.exists() && modified(&cached) >= modified(path) {
+        return Some(key.to_string());
+    }
+
+    let data = embedded_picture(path).or_else(|| folder_picture(path))?;
+    match save_cover(key, &data) {
+        Ok(()) => Some(key.to_string()),
+        Err(e) => {
+            println!("cache_cover: {}: {e}", path.display());
+            // Ein älteres Cover aus dem Cache ist besser als keins.
+            cached.exists().then(|| key.to_string())
+        }
+    }
+}
+
+fn embedded_picture(path: &Path) -> Option<Vec<u8>> {
+    let file = Probe::open(path)
+        .ok()?
+        .guess_file_type()
+        .ok()?
+        .read()
+        .ok()?;
+    let pictures = file
+        .tags()
+        .iter()
+        .flat_map(|t| t.pictures())
+        .collect::<Vec<_>>();
+    pictures
+        .iter()
+        .find(|p| p.pic_type() == PictureType::CoverFront)
+        .or_else(|| pictures.first())
+        .map(|p| p.data().to_vec())
+}
+
+fn folder_picture(path: &Path) -> Option<Vec<u8>> {
+    let dir = path.parent()?;
+    let entries = fs::read_dir(dir).ok()?;
+    let mut candidates = entries
+        .filter_map(Result::ok)
+        .filter_map(|e| {
+            let name = e.file_name().to_string_lossy().to_lowercase();
+            let rank = GL_COVER_FOLDER_FILES.iter().position(|f| *f == name)?;
+            Some((rank, e.path()))
+        })
+        .collect::<Vec<_>>();
+    candidates.sort();
+    candidates.into_iter().find_map(|(_, p)| fs::read(p).ok())
+}
+
+fn save_cover(key: &str, data: &[u8]) -> MyRes<()> {
+    fs::create_dir_all(cover_dir())?;
+    let img = image::load_from_memory(data)?;
+
+    for (thumb, size) in [(false, GL_COVER_MAX_SIZE), (true, GL_COVER_THUMB_SIZE)] {
+        let resized = if img.width() > size || img.height() > size {
+            img.resize(size, size, FilterType::Lanczos3)
+        } else {
+            img.clone()
+        };
+        let mut buf = Cursor::new(vec![]);
+        resized.to_rgb8().write_to(&mut buf, ImageFormat::Jpeg)?;
+
+        // Erst in eine temporäre Datei schreiben, damit nie ein halbes Bild ausgeliefert wird.
+        let target = cover_file(key, thumb);
+        let tmp = target.with_extension("tmp");
+        fs::write(&tmp, buf.into_inner())?;
+        fs::rename(tmp, target)?;
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct CoverQuery {
+    size: Option<String>,
+}
+
+#[get("/cover/{id}")]
+async fn net_cover_by_id(
+    req: HttpRequest,
+    id: web::Path<u32>,
+    query: web::Query<CoverQuery>,
+) -> MyRes<HttpResponse> {
+    println!("net_cover_by_id({id})");
+    db_update()?;
+    let id = id.into_inner();
+    let key = db_select("SELECT cover_key FROM songs WHERE id = ?", [id], |row| {
+        row.get::<_, Option<String>>(0)
+    })
+    .ok()
+    .flatten();
+
+    let thumb = query.size.as_deref() == Some("thumb");
+    let Some(file) = key.and_then(|k| NamedFile::open(cover_file(&k, thumb)).ok()) else {
+        return Ok(HttpResponse::NotFound().body("No cover for this song."));
+    };
+
+    let mut res = file
+        .use_etag(true)
+        .use_last_modified(true)
+        .into_response(&req);
+    res.headers_mut().insert(
+        CACHE_CONTROL,
+        HeaderValue::from_static("public, max-age=86400"),
+    );
+    Ok(res)
+}
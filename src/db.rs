@@ -1,9 +1,10 @@
-use std::{fs, path::Path};
+use std::time::Duration;
 
 use crate::{MyRes, GL_DBDIR};
-use color_eyre::eyre::{bail, eyre};
 use rusqlite::{Connection, Params};
-use stable_eyre::eyre::Context;
+
+// So lange wartet eine Verbindung auf die Schreibsperre, bevor sie aufgibt.
+const GL_BUSY_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub fn db_select<T, P, F>(sql: &str, params: P, f: F) -> MyRes<T>
 where
@@ -42,5 +43,10 @@ where
 }
 
 pub fn db_con() -> MyRes<Connection> {
-    Ok(Connection::open(GL_DBDIR.join("songdb.sqlite"))?)
+    let c = Connection::open(GL_DBDIR.join("songdb.sqlite"))?;
+    c.busy_timeout(GL_BUSY_TIMEOUT)?;
+    // Mit WAL blockiert ein Schreiber (z.B. ein Scan) keine Leser. Der Modus bleibt in
+    // der Datei gespeichert, ist er schon gesetzt, passiert hier nichts.
+    c.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))?;
+    Ok(c)
 }
@@ -0,0 +1,278 @@
+use std::collections::{BTreeMap, HashMap};
+
+use actix_web::{
+    get, post,
+    web::{Data, Json},
+    HttpResponse,
+};
+use color_eyre::eyre::{eyre, Context};
+use minijinja::context;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::db_con, song_from_row, update_manager::db_update, AppState, MyRes, Song, GL_SONG_COLUMNS,
+};
+
+// So viele Sekunden dürfen zwei Songs mit gleichen Tags auseinanderliegen.
+const GL_DUPLICATE_SECONDS_TOLERANCE: i32 = 2;
+
+#[derive(Serialize)]
+pub struct DuplicateGroup {
+    // Vorschlag für die Zeile, die nach dem Mergen übrig bleibt.
+    canonical: i32,
+    reasons: Vec<&'static str>,
+    songs: Vec<Song>,
+}
+
+struct Candidate {
+    song: Song,
+    fingerprint: Option<String>,
+}
+
+// Union-Find über die Indizes der Kandidaten.
+struct Groups {
+    parent: Vec<usize>,
+    reasons: HashMap<usize, Vec<&'static str>>,
+}
+
+impl Groups {
+    fn new(len: usize) -> Self {
+        Groups {
+            parent: (0..len).collect(),
+            reasons: HashMap::new(),
+        }
+    }
+
+    fn find(&mut self, i: usize) -> usize {
+        let mut root = i;
+        while self.parent[root] != root {
+            root = self.parent[root];
+        }
+        self.parent[i] = root;
+        root
+    }
+
+    fn union(&mut self, a: usize, b: usize, reason: &'static str) {
+        let (a, b) = (self.find(a), self.find(b));
+        let mut reasons = self.reasons.remove(&a).unwrap_or_default();
+        if a != b {
+            reasons.extend(self.reasons.remove(&b).unwrap_or_default());
+            self.parent[b] = a;
+        }
+        if !reasons.contains(&reason) {
+            reasons.push(reason);
+        }
+        self.reasons.insert(a, reasons);
+    }
+}
+
+fn normalize(s: &str) -> String {
+    s.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+fn find_duplicates(candidates: Vec<Candidate>) -> Vec<DuplicateGroup> {
+    let mut groups = Groups::new(candidates.len());
+
+    let mut by_fingerprint = HashMap::<&str, usize>::new();
+    for (i, c) in candidates.iter().enumerate() {
+        if let Some(fp) = &c.fingerprint {
+            if let Some(&first) = by_fingerprint.get(fp.as_str()) {
+                groups.union(first, i, "audio_hash");
+            } else {
+                by_fingerprint.insert(fp, i);
+            }
+        }
+    }
+
+    let mut by_tags = HashMap::<(String, String), Vec<usize>>::new();
+    for (i, c) in candidates.iter().enumerate() {
+        let key = (normalize(&c.song.artist), normalize(&c.song.songname));
+        if !key.0.is_empty() && !key.1.is_empty() {
+            by_tags.entry(key).or_default().push(i);
+        }
+    }
+    for mut members in by_tags.into_values() {
+        members.sort_by_key(|&i| candidates[i].song.seconds);
+        for pair in members.windows(2) {
+            let (a, b) = (&candidates[pair[0]].song, &candidates[pair[1]].song);
+            // Ohne bekannte Länge reichen gleiche Tags nicht.
+            if let (Some(a), Some(b)) = (a.seconds, b.seconds) {
+                if b - a <= GL_DUPLICATE_SECONDS_TOLERANCE {
+                    groups.union(pair[0], pair[1], "tags");
+                }
+            }
+        }
+    }
+
+    let mut members = BTreeMap::<usize, Vec<usize>>::new();
+    for i in 0..candidates.len() {
+        let root = groups.find(i);
+        members.entry(root).or_default().push(i);
+    }
+
+    let mut candidates = candidates.into_iter().map(Some).collect::<Vec<_>>();
+    members
+        .into_iter()
+        .filter(|(_, m)| m.len() > 1)
+        .map(|(root, m)| {
+            let songs = m
+                .into_iter()
+                .filter_map(|i| candidates[i].take().map(|c| c.song))
+                .collect::<Vec<_>>();
+            let canonical = songs
+                .iter()
+                .max_by_key(|s| (s.rating, s.times_played, -s.id))
+                .map(|s| s.id)
+                .unwrap_or_default();
+            DuplicateGroup {
+                canonical,
+                reasons: groups.reasons.remove(&root).unwrap_or_default(),
+                songs,
+            }
+        })
+        .collect()
+}
+
+fn get_duplicates() -> MyRes<Vec<DuplicateGroup>> {
+    println!("get_duplicates");
+    let sql = format!(
+        "select {GL_SONG_COLUMNS}, fingerprint from songs where deleted = 0 and merged_into is null"
+    );
+    let c = db_con()?;
+    let mut stmt = c.prepare(&sql).wrap_err("prepare")?;
+    let candidates = stmt
+        .query_map([], |row| {
+            Ok(Candidate {
+                song: song_from_row(row)?,
+                fingerprint: row.get("fingerprint")?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(find_duplicates(candidates))
+}
+
+#[get("/duplicates")]
+async fn net_duplicates() -> MyRes<Json<Vec<DuplicateGroup>>> {
+    println!("net_duplicates");
+    db_update()?;
+    Ok(Json(get_duplicates()?))
+}
+
+#[get("/web/duplicates")]
+async fn net_duplicates_web(app: Data<AppState>) -> MyRes<HttpResponse> {
+    println!("net_duplicates_web");
+    db_update()?;
+    let groups = get_duplicates()?;
+    let rendered = app.render_template("duplicates.html", context! {groups => &groups})?;
+    Ok(HttpResponse::Ok().body(rendered))
+}
+
+#[derive(Deserialize, Debug)]
+struct MergeRequest {
+    canonical: u32,
+    ids: Vec<u32>,
+}
+
+// Die anderen Songs werden auf canonical umgebogen: Playcounts werden addiert,
+// das beste Rating bleibt. Die Zeilen selbst bleiben erhalten, werden aber nicht
+// mehr zufällig ausgewählt.
+#[post("/duplicates/merge")]
+async fn net_duplicates_merge(data: Json<MergeRequest>) -> MyRes<String> {
+    println!("net_duplicates_merge({data:?})");
+    db_update()?;
+    let MergeRequest { canonical, ids } = data.into_inner();
+    let ids = ids
+        .into_iter()
+        .filter(|&id| id != canonical)
+        .collect::<Vec<_>>();
+
+    let mut db = db_con()?;
+    let t = db.transaction().wrap_err("transaction")?;
+    let (mut times_played, mut rating) = t
+        .query_row(
+            "SELECT times_played, rating FROM songs WHERE id = ? AND merged_into IS NULL",
+            [canonical],
+            |row| Ok((row.get::<_, u32>(0)?, row.get::<_, u32>(1)?)),
+        )
+        .map_err(|_| eyre!("Song {canonical} not found or already merged"))?;
+
+    for id in &ids {
+        let (played, r) = t
+            .query_row(
+                "SELECT times_played, rating FROM songs WHERE id = ? AND merged_into IS NULL",
+                [id],
+                |row| Ok((row.get::<_, u32>(0)?, row.get::<_, u32>(1)?)),
+            )
+            .map_err(|_| eyre!("Song {id} not found or already merged"))?;
+        times_played += played;
+        rating = rating.max(r);
+        t.execute(
+            "UPDATE songs SET merged_into = ?, times_played = 0 WHERE id = ?",
+            (canonical, id),
+        )?;
+        // Falls der Song vorher selbst Ziel eines Merges war.
+        t.execute(
+            "UPDATE songs SET merged_into = ? WHERE merged_into = ?",
+            (canonical, id),
+        )?;
+    }
+    t.execute(
+        "UPDATE songs SET times_played = ?, rating = ? WHERE id = ?",
+        (times_played, rating, canonical),
+    )?;
+    t.commit().wrap_err("commit")?;
+
+    Ok(format!(
+        "Merged {} songs into {canonical}. Played: {times_played}, Rating: {rating}",
+        ids.len()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_duplicates, Candidate};
+    use crate::Song;
+
+    fn candidate(id: i32, artist: &str, songname: &str, seconds: i32, fp: &str) -> Candidate {
+        Candidate {
+            song: Song {
+                id,
+                path: format!("/music/{id}.mp3"),
+                filename: format!("{id}.mp3"),
+                songname: songname.to_string(),
+                artist: artist.to_string(),
+                seconds: Some(seconds),
+                rating: id,
+                ..Default::default()
+            },
+            fingerprint: (!fp.is_empty()).then(|| fp.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_find_duplicates() {
+        let groups = find_duplicates(vec![
+            candidate(1, "Artist", "Song", 200, "aaa"),
+            candidate(2, "artist", "Song!", 201, ""),
+            candidate(3, "Artist", "Song", 260, ""),
+            candidate(4, "", "", 100, "aaa"),
+            candidate(5, "Other", "Song", 200, "bbb"),
+            candidate(6, "", "", 50, "ccc"),
+            candidate(7, "", "", 60, "ccc"),
+        ]);
+
+        assert_eq!(groups.len(), 2);
+
+        let ids = groups[0].songs.iter().map(|s| s.id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![1, 2, 4]);
+        assert_eq!(groups[0].canonical, 4);
+        assert_eq!(groups[0].reasons, vec!["audio_hash", "tags"]);
+
+        let ids = groups[1].songs.iter().map(|s| s.id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![6, 7]);
+        assert_eq!(groups[1].reasons, vec!["audio_hash"]);
+    }
+}
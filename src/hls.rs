@@ -0,0 +1,147 @@
+use std::path::{Path, PathBuf};
+
+use actix_web::{get, http::header::CACHE_CONTROL, web, HttpRequest, HttpResponse};
+
+use crate::{
+    audio::get_songlength, db::db_select, get_file_by_name, get_songpath_by_id, plays::count_play,
+    transcode::get_segments, update_manager::db_update, MyRes,
+};
+
+const GL_HLS_SEGMENT_SECS: u64 = 10;
+// Varianten für adaptive Wiedergabe, der Player wählt je nach Bandbreite.
+const GL_HLS_BITRATES: &[u32] = &[64, 128, 192];
+const GL_HLS_PLAYLIST_TYPE: &str = "application/vnd.apple.mpegurl";
+
+fn song_duration_ms(id: u32, path: &str) -> u64 {
+    match db_select("SELECT duration_ms FROM songs WHERE id = ?", [id], |row| {
+        row.get::<_, Option<u64>>(0)
+    }) {
+        Ok(Some(ms)) if ms > 0 => ms,
+        _ => get_songlength(Path::new(path))
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default(),
+    }
+}
+
+fn segment_count(duration_ms: u64) -> u64 {
+    duration_ms.div_ceil(GL_HLS_SEGMENT_SECS * 1000).max(1)
+}
+
+fn master_playlist() -> String {
+    let mut out = "#EXTM3U\n#EXT-X-VERSION:3\n".to_string();
+    for bitrate in GL_HLS_BITRATES {
+        // Etwas Luft für den Overhead von MPEG-TS.
+        let bandwidth = bitrate * 1100;
+        out += &format!(
+            "#EXT-X-STREAM-INF:BANDWIDTH={bandwidth},CODECS=\"mp4a.40.2\"\n{bitrate}/playlist.m3u8\n"
+        );
+    }
+    out
+}
+
+fn media_playlist(duration_ms: u64) -> String {
+    let mut out = format!(
+        "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-PLAYLIST-TYPE:VOD\n#EXT-X-TARGETDURATION:{GL_HLS_SEGMENT_SECS}\n#EXT-X-MEDIA-SEQUENCE:0\n"
+    );
+    let count = segment_count(duration_ms);
+    for i in 0..count {
+        let length_ms = if i + 1 == count {
+            duration_ms
+                .saturating_sub(i * GL_HLS_SEGMENT_SECS * 1000)
+                .max(1)
+        } else {
+            GL_HLS_SEGMENT_SECS * 1000
+        };
+        out += &format!(
+            "#EXTINF:{}.{:03},\n{i}.ts\n",
+            length_ms / 1000,
+            length_ms % 1000
+        );
+    }
+    out + "#EXT-X-ENDLIST\n"
+}
+
+#[get("/hls/{id}/master.m3u8")]
+async fn net_hls_master(id: web::Path<u32>) -> MyRes<HttpResponse> {
+    println!("net_hls_master({id})");
+    db_update()?;
+    get_songpath_by_id(id.into_inner())?;
+    Ok(HttpResponse::Ok()
+        .content_type(GL_HLS_PLAYLIST_TYPE)
+        .body(master_playlist()))
+}
+
+#[get("/hls/{id}/{bitrate}/playlist.m3u8")]
+async fn net_hls_playlist(path: web::Path<(u32, u32)>) -> MyRes<HttpResponse> {
+    println!("net_hls_playlist({path:?})");
+    db_update()?;
+    let (id, bitrate) = path.into_inner();
+    if !GL_HLS_BITRATES.contains(&bitrate) {
+        return Ok(HttpResponse::NotFound().body("Unknown bitrate."));
+    }
+    let song = get_songpath_by_id(id)?;
+    Ok(HttpResponse::Ok()
+        .content_type(GL_HLS_PLAYLIST_TYPE)
+        .body(media_playlist(song_duration_ms(id, &song))))
+}
+
+#[get("/hls/{id}/{bitrate}/{segment}.ts")]
+async fn net_hls_segment(
+    req: HttpRequest,
+    path: web::Path<(u32, u32, u64)>,
+) -> MyRes<HttpResponse> {
+    println!("net_hls_segment({path:?})");
+    db_update()?;
+    let (id, bitrate, segment) = path.into_inner();
+    if !GL_HLS_BITRATES.contains(&bitrate) {
+        return Ok(HttpResponse::NotFound().body("Unknown bitrate."));
+    }
+    let song = get_songpath_by_id(id)?;
+    let count = segment_count(song_duration_ms(id, &song));
+    if segment >= count {
+        return Ok(HttpResponse::NotFound().body("No such segment."));
+    }
+
+    let source = PathBuf::from(&song);
+    let dir = web::block(move || {
+        get_segments(&source, bitrate, GL_HLS_SEGMENT_SECS).map_err(|e| e.to_string())
+    })
+    .await??;
+    let file = dir.join(format!("{segment}.ts"));
+    if !file.exists() {
+        return Ok(HttpResponse::NotFound().body("No such segment."));
+    }
+
+    let mut res = get_file_by_name(&file.to_string_lossy())?
+        .set_content_type("video/mp2t".parse()?)
+        .disable_content_disposition()
+        .into_response(&req);
+    res.headers_mut()
+        .insert(CACHE_CONTROL, "public, max-age=3600".parse()?);
+    if segment == 0 {
+        if let Err(e) = count_play(&req, &res, id, "hls") {
+            println!("net_hls_segment: could not count play of {id}: {e}");
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::media_playlist;
+
+    #[test]
+    fn test_media_playlist() {
+        let playlist = media_playlist(25_432);
+        let segments = playlist
+            .lines()
+            .filter(|l| l.starts_with("#EXTINF"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            segments,
+            vec!["#EXTINF:10.000,", "#EXTINF:10.000,", "#EXTINF:5.432,"]
+        );
+        assert!(playlist.contains("\n2.ts\n"));
+        assert!(playlist.ends_with("#EXT-X-ENDLIST\n"));
+    }
+}
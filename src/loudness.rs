@@ -0,0 +1,300 @@
+use std::{io::ErrorKind, path::Path, process::Command};
+
+use color_eyre::eyre::eyre;
+use ebur128::{EbuR128, Mode};
+use rusqlite::{named_params, Connection, OptionalExtension};
+use symphonia::core::{
+    audio::SampleBuffer, codecs::DecoderOptions, errors::Error as SymphoniaError,
+};
+
+use crate::{audio::open_audio, db::db_select, MyRes, GL_FFMPEG};
+
+// Referenzpegel von ReplayGain 2.0.
+const GL_REPLAYGAIN_REFERENCE_LUFS: f64 = -18.0;
+
+// Alben werden über Album und Album-Artist (sonst Artist) zusammengefasst.
+const GL_ALBUM_KEY_SQL: &str =
+    "album = :album AND coalesce(nullif(album_artist, ''), artist) = :artist";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Loudness {
+    // Integrierte Lautheit nach EBU R128 in LUFS.
+    pub integrated: f64,
+    // Sample-Peak, linear (1.0 = 0 dBFS).
+    pub peak: f64,
+}
+
+// symphonia kann z.B. kein Opus dekodieren, dann misst ffmpeg.
+pub fn measure_loudness(path: &Path) -> MyRes<Loudness> {
+    decode_loudness(path)
+        .or_else(|e| ffmpeg_loudness(path).map_err(|f| eyre!("symphonia: {e}; ffmpeg: {f}").into()))
+}
+
+fn decode_loudness(path: &Path) -> MyRes<Loudness> {
+    let mut format = open_audio(path)?;
+    let track = format
+        .default_track()
+        .ok_or_else(|| eyre!("no audio track"))?;
+    let track_id = track.id;
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let mut ebu: Option<EbuR128> = None;
+    let mut samples: Option<SampleBuffer<f32>> = None;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(p) if p.track_id() == track_id => p,
+            Ok(_) => continue,
+            Err(SymphoniaError::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => Err(e)?,
+        };
+        let decoded = match decoder.decode(&packet) {
+            Ok(d) => d,
+            // Einzelne kaputte Frames überspringen.
+            Err(SymphoniaError::DecodeError(_)) => continue,
+            Err(e) => Err(e)?,
+        };
+
+        let spec = *decoded.spec();
+        if ebu.is_none() {
+            ebu = Some(EbuR128::new(
+                spec.channels.count() as u32,
+                spec.rate,
+                Mode::I | Mode::SAMPLE_PEAK,
+            )?);
+        }
+        if samples
+            .as_ref()
+            .is_none_or(|s| s.capacity() < decoded.capacity() * spec.channels.count())
+        {
+            samples = Some(SampleBuffer::new(decoded.capacity() as u64, spec));
+        }
+        if let (Some(ebu), Some(samples)) = (&mut ebu, &mut samples) {
+            samples.copy_interleaved_ref(decoded);
+            ebu.add_frames_f32(samples.samples())?;
+        }
+    }
+
+    let ebu = ebu.ok_or_else(|| eyre!("no audio decoded"))?;
+    let integrated = ebu.loudness_global()?;
+    if !integrated.is_finite() {
+        return Err(eyre!("silent track").into());
+    }
+    let channels = ebu.channels();
+    let mut peak = 0f64;
+    for channel in 0..channels {
+        peak = peak.max(ebu.sample_peak(channel)?);
+    }
+    Ok(Loudness { integrated, peak })
+}
+
+fn ffmpeg_loudness(path: &Path) -> MyRes<Loudness> {
+    let output = Command::new(&*GL_FFMPEG)
+        .args([
+            "-nostdin",
+            "-hide_banner",
+            "-nostats",
+            "-loglevel",
+            "info",
+            "-i",
+        ])
+        .arg(path)
+        .args([
+            "-map",
+            "0:a:0",
+            "-af",
+            "ebur128=peak=sample",
+            "-f",
+            "null",
+            "-",
+        ])
+        .output()
+        .map_err(|e| eyre!("Could not start {}: {e}", *GL_FFMPEG))?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        return Err(eyre!("{}", stderr.trim()).into());
+    }
+    parse_ebur128_summary(&stderr).ok_or_else(|| eyre!("no ebur128 summary").into())
+}
+
+// Liest "I: -16.6 LUFS" und "Peak: -0.3 dBFS" aus der Zusammenfassung am Ende.
+fn parse_ebur128_summary(log: &str) -> Option<Loudness> {
+    let summary = &log[log.rfind("Summary:")?..];
+    let value = |label: &str, unit: &str| {
+        summary.lines().find_map(|line| {
+            line.trim()
+                .strip_prefix(label)?
+                .trim()
+                .strip_suffix(unit)?
+                .trim()
+                .parse::<f64>()
+                .ok()
+        })
+    };
+    let integrated = value("I:", "LUFS").filter(|i| *i > -70.0)?;
+    let peak = 10f64.powf(value("Peak:", "dBFS")? / 20.0);
+    Some(Loudness { integrated, peak })
+}
+
+// Fasst die Tracks eines Albums zusammen. Die Lautheit wird nach Energie und
+// Länge gewichtet gemittelt, der Peak ist das Maximum.
+fn combine_loudness(tracks: &[(Loudness, f64)]) -> Option<Loudness> {
+    let weight = tracks.iter().map(|(_, secs)| secs.max(1.0)).sum::<f64>();
+    if tracks.is_empty() || weight <= 0.0 {
+        return None;
+    }
+    let energy = tracks
+        .iter()
+        .map(|(l, secs)| secs.max(1.0) * 10f64.powf(l.integrated / 10.0))
+        .sum::<f64>();
+    Some(Loudness {
+        integrated: 10.0 * (energy / weight).log10(),
+        peak: tracks.iter().map(|(l, _)| l.peak).fold(0.0, f64::max),
+    })
+}
+
+// (album, artist) des Songs, wenn er zu einem Album gehört.
+pub fn album_key(c: &Connection, id: u32) -> MyRes<Option<(String, String)>> {
+    let key = c
+        .query_row(
+            "SELECT album, coalesce(nullif(album_artist, ''), artist) FROM songs WHERE id = ?",
+            [id],
+            |row| {
+                Ok((
+                    row.get::<_, Option<String>>(0)?.unwrap_or_default(),
+                    row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                ))
+            },
+        )
+        .optional()?;
+    Ok(key.filter(|(album, _)| !album.is_empty()))
+}
+
+pub fn update_album_loudness(c: &Connection, (album, artist): &(String, String)) -> MyRes<()> {
+    let mut stmt = c.prepare_cached(&format!(
+        "SELECT track_loudness, track_peak, seconds FROM songs
+        WHERE {GL_ALBUM_KEY_SQL} AND deleted = 0 AND merged_into IS NULL AND track_loudness IS NOT NULL"
+    ))?;
+    let tracks = stmt
+        .query_map(named_params! {":album": album, ":artist": artist}, |row| {
+            Ok((
+                Loudness {
+                    integrated: row.get(0)?,
+                    peak: row.get(1)?,
+                },
+                row.get::<_, Option<f64>>(2)?.unwrap_or_default(),
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let combined = combine_loudness(&tracks);
+    c.prepare_cached(&format!(
+        "UPDATE songs SET album_loudness = :loudness, album_peak = :peak WHERE {GL_ALBUM_KEY_SQL}"
+    ))?
+    .execute(named_params! {
+        ":loudness": combined.map(|l| l.integrated),
+        ":peak": combined.map(|l| l.peak),
+        ":album": album,
+        ":artist": artist,
+    })?;
+    Ok(())
+}
+
+// Verstärkung in dB, um auf den ReplayGain-Referenzpegel zu kommen. Wird so
+// begrenzt, dass der Peak nicht über 0 dBFS geht.
+pub fn replay_gain(l: Loudness) -> f64 {
+    let gain = GL_REPLAYGAIN_REFERENCE_LUFS - l.integrated;
+    if l.peak > 0.0 {
+        gain.min(-20.0 * l.peak.log10())
+    } else {
+        gain
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GainMode {
+    Track,
+    Album,
+}
+
+impl GainMode {
+    pub fn parse(mode: &str) -> Option<GainMode> {
+        match mode {
+            "track" => Some(GainMode::Track),
+            "album" => Some(GainMode::Album),
+            _ => None,
+        }
+    }
+}
+
+pub fn loudness_from_row(
+    row: &rusqlite::Row,
+    loudness: &str,
+    peak: &str,
+) -> Result<Option<Loudness>, rusqlite::Error> {
+    Ok(row
+        .get::<_, Option<f64>>(loudness)?
+        .zip(row.get::<_, Option<f64>>(peak)?)
+        .map(|(integrated, peak)| Loudness { integrated, peak }))
+}
+
+// Gain für die Wiedergabe auf dem Server. Ohne Albumwerte gilt der Track.
+pub fn song_gain(id: u32, mode: GainMode) -> Option<f64> {
+    let (track, album) = db_select(
+        "SELECT track_loudness, track_peak, album_loudness, album_peak FROM songs WHERE id = ?",
+        [id],
+        |row| {
+            Ok((
+                loudness_from_row(row, "track_loudness", "track_peak")?,
+                loudness_from_row(row, "album_loudness", "album_peak")?,
+            ))
+        },
+    )
+    .ok()?;
+    let loudness = match mode {
+        GainMode::Album => album.or(track),
+        GainMode::Track => track,
+    }?;
+    Some(replay_gain(loudness))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{combine_loudness, parse_ebur128_summary, replay_gain, Loudness};
+
+    #[test]
+    fn test_combine_loudness() {
+        let l = |integrated, peak| Loudness { integrated, peak };
+        let album = combine_loudness(&[(l(-10.0, 0.9), 100.0), (l(-20.0, 0.5), 100.0)]).unwrap();
+        // Das lautere Stück dominiert den Mittelwert.
+        assert!((album.integrated - -12.596).abs() < 0.01);
+        assert_eq!(album.peak, 0.9);
+        assert!(combine_loudness(&[]).is_none());
+
+        assert!((replay_gain(l(-23.0, 0.1)) - 5.0).abs() < 1e-9);
+        // Begrenzt durch den Peak: 0.9 darf nur um ~0.9 dB angehoben werden.
+        assert!((replay_gain(l(-30.0, 0.9)) - 0.915).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_parse_ebur128_summary() {
+        let log = "[Parsed_ebur128_0 @ 0x5581] Summary:
+
+  Integrated loudness:
+    I:         -16.6 LUFS
+    Threshold: -27.0 LUFS
+
+  Loudness range:
+    LRA:         4.8 LU
+
+  Sample peak:
+    Peak:       -6.0 dBFS
+";
+        let l = parse_ebur128_summary(log).unwrap();
+        assert_eq!(l.integrated, -16.6);
+        assert!((l.peak - 0.501).abs() < 0.001);
+        assert!(parse_ebur128_summary("Stream #0:0: Audio: opus").is_none());
+        // Stille hat keine sinnvolle Lautheit.
+        assert!(parse_ebur128_summary(&log.replace("-16.6", "-70.0")).is_none());
+    }
+}
@@ -1,14 +1,17 @@
 use actix_files::NamedFile;
 use actix_multipart::Multipart;
 use actix_web::{
-    error::ErrorNotFound,
+    error::{ErrorInternalServerError, ErrorNotFound},
     get,
-    http::header::{ContentDisposition, DispositionParam, DispositionType},
-    post,
+    http::header::{
+        ContentDisposition, DispositionParam, DispositionType, CACHE_CONTROL, LOCATION,
+    },
+    middleware::from_fn,
+    post, route,
     web::{self, Data, Json},
-    App, HttpResponse, HttpServer,
+    App, HttpRequest, HttpResponse, HttpServer,
 };
-use color_eyre::eyre::{eyre, Context};
+use color_eyre::eyre::Context;
 use color_eyre::{install, Result};
 use db::*;
 use futures_util::StreamExt;
@@ -18,56 +21,163 @@ use minijinja::{context, Environment};
 use minijinja::{path_loader, Value};
 use minijinja_autoreload::AutoReloader;
 use rand::{distributions::WeightedIndex, prelude::Distribution, thread_rng};
-use rusqlite::Statement;
+use rusqlite::{named_params, Connection, OptionalExtension};
 use serde::{Deserialize, Serialize};
+use std::path::Path;
 use std::{env, fs::File, io::Write, path::PathBuf};
-use std::{
-    path::Path,
-    sync::{Arc, Mutex},
-};
-use walkdir::WalkDir;
 
+use crate::audio::{
+    check_filename_pattern, get_fingerprint, get_mime_type, get_songlength, read_tags, write_tags,
+    SongTags,
+};
+use crate::cover::{cache_cover, get_cover_key, net_cover_by_id};
+use crate::duplicates::{net_duplicates, net_duplicates_merge, net_duplicates_web};
+use crate::hls::{net_hls_master, net_hls_playlist, net_hls_segment};
+use crate::loudness::{
+    album_key, loudness_from_row, measure_loudness, replay_gain, update_album_loudness, GainMode,
+    Loudness,
+};
+use crate::plays::{count_play, net_finished, net_history, net_skip};
+use crate::radio::net_radio;
+use crate::ranges::song_ranges;
+use crate::recent::{net_recent, net_recent_web};
+use crate::scanner::{file_stamp_by_path, net_update_cancel, net_update_files, net_update_status};
+use crate::sessions::{
+    net_shuffle_reset, net_shuffle_session, push_history, session_history, shuffle_session,
+};
+use crate::shuffle::{pick_with_spread, Candidate, ReplayWindows, ShuffleQuery, ShuffleStrategy};
+use crate::stats::{net_stats, net_stats_web};
+use crate::transcode::{
+    cached_transcode, stream_transcoded, supported_formats, Transcode, TranscodeQuery,
+};
 use crate::update_manager::db_update;
+use crate::watcher::start_watcher;
 
+mod audio;
+mod cover;
 mod db;
+mod duplicates;
+mod hls;
+mod loudness;
+mod plays;
+mod radio;
+mod ranges;
+mod recent;
+mod scanner;
+mod sessions;
+mod shuffle;
+mod stats;
+mod transcode;
 mod update_manager;
+mod watcher;
 
 type MyRes<T> = Result<T, Box<dyn std::error::Error>>;
 
-type Mylist = Arc<Mutex<Vec<i32>>>;
-
 lazy_static! {
-    static ref LAST_SONGS: Mylist = Arc::new(Mutex::new(Vec::new()));
     static ref GL_PORT: i16 = env::var("PORT")
         .map(|v| v.parse::<i16>().unwrap_or(3000))
         .unwrap_or(3000);
-    static ref GL_MUSICDIR: PathBuf = env::var("MUSICDIR").and_then(|s| Ok(PathBuf::from(s))).unwrap_or(
+    static ref GL_MUSICDIR: PathBuf = env::var("MUSICDIR").map(PathBuf::from).unwrap_or(
         PathBuf::from(env!("CARGO_MANIFEST_DIR"))
             .join("music")
     );
     // /music-srv/db/
-    static ref GL_DBDIR: PathBuf = env::var("DBDIR").and_then(|s| Ok(PathBuf::from(s))).unwrap_or(
+    static ref GL_DBDIR: PathBuf = env::var("DBDIR").map(PathBuf::from).unwrap_or(
         PathBuf::from(env!("CARGO_MANIFEST_DIR"))
     );
-    static ref GL_UPLOADDIR: PathBuf = env::var("UPLOADDIR").and_then(|s| Ok(PathBuf::from(s))).unwrap_or(
+    // Mit WATCH=1 wird MUSICDIR überwacht und die songs-Tabelle automatisch nachgezogen.
+    static ref GL_WATCH: bool = env::var("WATCH")
+        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false);
+    static ref GL_WATCH_DEBOUNCE_SECS: u64 = env::var("WATCH_DEBOUNCE_SECS")
+        .map(|v| v.parse::<u64>().unwrap_or(2))
+        .unwrap_or(2);
+    // Fallback für Dateien ohne Tags, relativ zu MUSICDIR.
+    static ref GL_FILENAME_PATTERN: String = env::var("FILENAME_PATTERN")
+        .unwrap_or("{artist}/{album}/{track} - {title}".to_string());
+    static ref GL_CACHEDIR: PathBuf = env::var("CACHEDIR").map(PathBuf::from).unwrap_or(
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("cache")
+    );
+    static ref GL_FFMPEG: String = env::var("FFMPEG").unwrap_or("ffmpeg".to_string());
+    // Obergrenze für GL_CACHEDIR/transcode, älteste Dateien werden zuerst gelöscht.
+    static ref GL_TRANSCODE_CACHE_MB: u64 = env::var("TRANSCODE_CACHE_MB")
+        .map(|v| v.parse::<u64>().unwrap_or(1024))
+        .unwrap_or(1024);
+    static ref GL_RADIO_BITRATE: u32 = env::var("RADIO_BITRATE")
+        .map(|v| v.parse::<u32>().unwrap_or(128))
+        .unwrap_or(128);
+    // track oder album: Lautstärke im Radio per ReplayGain angleichen.
+    static ref GL_RADIO_GAIN: Option<GainMode> = env::var("RADIO_GAIN")
+        .ok()
+        .and_then(|v| GainMode::parse(&v));
+    // Automatisches Bewerten über /skip und /finished, 0 schaltet es ab:
+    // so viele frühe Skips in Folge senken das Rating um 1,
+    static ref GL_AUTO_RATING_SKIPS: u32 = env::var("AUTO_RATING_SKIPS")
+        .map(|v| v.parse::<u32>().unwrap_or(0))
+        .unwrap_or(0);
+    // so oft zu Ende gehört in Folge hebt es um 1.
+    static ref GL_AUTO_RATING_FINISHES: u32 = env::var("AUTO_RATING_FINISHES")
+        .map(|v| v.parse::<u32>().unwrap_or(0))
+        .unwrap_or(0);
+    // Ein Skip innerhalb dieser Zeit nach Start zählt als früh.
+    static ref GL_EARLY_SKIP_SECS: u64 = env::var("EARLY_SKIP_SECS")
+        .map(|v| v.parse::<u64>().unwrap_or(30))
+        .unwrap_or(30);
+    // Likes/Dislikes (vote) verschieben das Gewicht um diesen Faktor pro Stimme,
+    // aber höchstens um VOTE_CAP Stimmen in jede Richtung. 1 schaltet es ab.
+    static ref GL_VOTE_FACTOR: f32 = env::var("VOTE_FACTOR")
+        .map(|v| v.parse::<f32>().unwrap_or(1.2))
+        .unwrap_or(1.2);
+    static ref GL_VOTE_CAP: i32 = env::var("VOTE_CAP")
+        .map(|v| v.parse::<i32>().unwrap_or(5))
+        .unwrap_or(5);
+    // Wie viele der letzten zufälligen Songs denselben Artist bzw. dasselbe Album sperren.
+    static ref GL_ARTIST_REPLAY_PROTECTION: usize = env::var("ARTIST_REPLAY_PROTECTION")
+        .map(|v| v.parse::<usize>().unwrap_or(3))
+        .unwrap_or(3);
+    static ref GL_ALBUM_REPLAY_PROTECTION: usize = env::var("ALBUM_REPLAY_PROTECTION")
+        .map(|v| v.parse::<usize>().unwrap_or(5))
+        .unwrap_or(5);
+    static ref GL_UPLOADDIR: PathBuf = env::var("UPLOADDIR").map(PathBuf::from).unwrap_or(
         PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("music").join("upload")
     );
 }
 
-const GL_INSERT_SONG_STMT: &str = "INSERT INTO songs (path, filename, songname, artist, album, length, seconds, rating, vote, deleted)
-            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+const GL_INSERT_SONG_STMT: &str = "INSERT INTO songs (path, filename, songname, artist, album, length, seconds, rating, vote, deleted, mtime, size, fingerprint,
+                album_artist, track_number, disc_number, year, genre, composer, comment, metadata_source, cover_key, track_loudness, track_peak, duration_ms, duration_error, loudness_error)
+            VALUES (:path, :filename, :songname, :artist, :album, :length, :seconds, :rating, :vote, :deleted, :mtime, :size, :fingerprint,
+                :album_artist, :track_number, :disc_number, :year, :genre, :composer, :comment, :metadata_source, :cover_key, :track_loudness, :track_peak, :duration_ms, :duration_error, :loudness_error)
             ON CONFLICT (path) DO UPDATE SET
-            songname=excluded.songname,
-            artist=excluded.artist,
-            album=excluded.album,
             length=excluded.length,
             seconds=excluded.seconds,
-            deleted=excluded.deleted";
+            deleted=excluded.deleted,
+            mtime=excluded.mtime,
+            size=excluded.size,
+            fingerprint=excluded.fingerprint,
+            cover_key=excluded.cover_key,
+            track_loudness=excluded.track_loudness,
+            track_peak=excluded.track_peak,
+            duration_ms=excluded.duration_ms,
+            duration_error=excluded.duration_error,
+            loudness_error=excluded.loudness_error,
+            songname=iif(tags_locked, songname, excluded.songname),
+            artist=iif(tags_locked, artist, excluded.artist),
+            album=iif(tags_locked, album, excluded.album),
+            album_artist=iif(tags_locked, album_artist, excluded.album_artist),
+            track_number=iif(tags_locked, track_number, excluded.track_number),
+            disc_number=iif(tags_locked, disc_number, excluded.disc_number),
+            year=iif(tags_locked, year, excluded.year),
+            genre=iif(tags_locked, genre, excluded.genre),
+            composer=iif(tags_locked, composer, excluded.composer),
+            comment=iif(tags_locked, comment, excluded.comment),
+            metadata_source=iif(tags_locked, metadata_source, excluded.metadata_source)";
 
 const GL_RATING_BASE: i32 = 2i32;
 const GL_DEFAULT_RATING_SCALE: f32 = 2.5f32;
 const GL_DEBUG_SIZE: bool = false;
 const GL_REPLAY_PROTECTION: usize = 15;
+// length für Songs, deren Länge nicht bestimmt werden konnte.
+const GL_UNKNOWN_LENGTH: &str = "?";
 
 struct AppState {
     template_env: AutoReloader,
@@ -87,6 +197,14 @@ async fn main() -> std::io::Result<()> {
     install().unwrap();
     println!("http://localhost:{}", *GL_PORT);
     println!("MUSICDIR: {}", GL_MUSICDIR.to_str().unwrap_or_default());
+    if let Err(e) = check_filename_pattern(&GL_FILENAME_PATTERN) {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+
+    if *GL_WATCH {
+        start_watcher();
+    }
 
     let ext = web::Data::new(AppState {
         template_env: AutoReloader::new(|notifier| {
@@ -101,22 +219,48 @@ async fn main() -> std::io::Result<()> {
 
     HttpServer::new(move || {
         App::new()
+            .service(net_update_status)
+            .service(net_update_cancel)
             .service(net_update_files)
             .service(net_songlist)
+            .service(net_duration_errors)
+            .service(net_loudness_errors)
             .service(net_get_random_id)
             .service(net_get_random_id_with_scale)
             .service(net_song_random)
             .service(net_song_by_id)
             .service(net_song_upvote_by_id)
             .service(net_song_downvote_by_id)
+            .service(net_song_like_by_id)
+            .service(net_song_dislike_by_id)
+            .service(net_song_resetvote_by_id)
             .service(net_songdata_by_id)
             .service(net_songdata_pretty_by_id)
+            .service(net_cover_by_id)
+            .service(net_radio)
+            .service(net_history)
+            .service(net_skip)
+            .service(net_finished)
+            .service(net_recent)
+            .service(net_recent_web)
+            .service(net_shuffle_session)
+            .service(net_shuffle_reset)
+            .service(net_stats)
+            .service(net_stats_web)
+            .service(net_hls_master)
+            .service(net_hls_playlist)
+            .service(net_hls_segment)
             .service(net_404)
             .service(net_ping)
             .service(net_index)
             .service(net_songlist_web)
+            .service(net_duplicates)
+            .service(net_duplicates_web)
+            .service(net_duplicates_merge)
             .service(net_upload)
             .service(net_update_songdata_by_id_post)
+            .service(net_update_songdata_post)
+            .service(net_write_tags)
             .app_data(ext.clone())
     })
     // .bind(format!(":{}", *GL_PORT))?
@@ -143,108 +287,198 @@ async fn net_ping() -> MyRes<String> {
     Ok("pong".to_string())
 }
 
-#[get("/update")]
-async fn net_update_files() -> MyRes<String> {
-    println!("net_update_files");
-    db_update()?;
-
-    let mut size: u64 = 0;
-    let mut count = 0;
-
-    let mut db = db_con()?;
-    let b = db.transaction().wrap_err("transaction")?;
-    let mut s = b.prepare(GL_INSERT_SONG_STMT).wrap_err("prepare")?;
-
-    b.execute("UPDATE songs set deleted = 1", [])
-        .wrap_err("delete")?;
-
-    let files = WalkDir::new(&*GL_MUSICDIR);
-    files
-        .into_iter()
-        .filter_map(Result::ok)
-        .filter(|f| {
-            f.path()
-                .to_str()
-                .unwrap_or("")
-                .to_lowercase()
-                .ends_with(".mp3")
-        })
-        .for_each(|entry| {
-            if GL_DEBUG_SIZE {
-                size += match entry.metadata() {
-                    Ok(ok) => ok.len(),
-                    Err(_) => 0,
-                };
-            }
-            let path = entry.path().display().to_string();
-            let filename = entry.file_name().to_string_lossy();
-
-            add_song_in_transaction(&path, &filename, &mut s);
+// Alles, was beim Einlesen aus der Datei gelesen wird. Das dauert (Decodieren für
+// Länge und Lautheit, Fingerprint, Cover) und passiert deshalb außerhalb jeder
+// Transaktion, damit die Datenbank währenddessen nicht gesperrt ist.
+pub struct ProbedSong {
+    path: String,
+    filename: String,
+    mtime: i64,
+    size: i64,
+    tags: SongTags,
+    duration_ms: Option<u64>,
+    duration_error: Option<String>,
+    loudness: Option<Loudness>,
+    loudness_error: Option<String>,
+    fingerprint: Option<String>,
+    cover_key: Option<String>,
+}
 
-            count += 1;
-            if count % 1000 == 0 {
-                println!("net_update_files count: {count}");
-            }
-        });
-    if GL_DEBUG_SIZE {
-        println!("{size}");
+pub fn probe_song(path: &str, filename: &str, mtime: i64, size: i64) -> ProbedSong {
+    println!("probe_song({path}, {filename})");
+    let tags = read_tags(Path::new(path));
+
+    let duration = get_songlength(Path::new(path))
+        .inspect_err(|e| println!("probe_song: no duration for {path}: {e}"));
+    let loudness = measure_loudness(Path::new(path))
+        .inspect_err(|e| println!("probe_song: no loudness for {path}: {e}"));
+    let fingerprint = get_fingerprint(Path::new(path))
+        .inspect_err(|e| println!("probe_song: no fingerprint for {path}: {e}"))
+        .ok();
+    let cover_key = cache_cover(Path::new(path), &get_cover_key(Path::new(path), &tags));
+
+    ProbedSong {
+        path: path.to_string(),
+        filename: filename.to_string(),
+        mtime,
+        size,
+        duration_ms: duration.as_ref().ok().map(|d| d.as_millis() as u64),
+        duration_error: duration.as_ref().err().map(|e| e.to_string()),
+        tags,
+        loudness: loudness.as_ref().ok().copied(),
+        loudness_error: loudness.as_ref().err().map(|e| e.to_string()),
+        fingerprint,
+        cover_key,
     }
-
-    drop(s);
-    b.commit().wrap_err("commit")?;
-
-    Ok("Ok".to_string())
 }
 
-fn add_song_in_transaction(path: &str, filename: &str, s: &mut Statement) {
-    println!("add_song_in_transaction({path}, {filename})");
-    let songname;
-    let artist;
-    let album;
-
-    if let Ok(tags) = audiotags::Tag::new()
-        .with_tag_type(audiotags::TagType::Id3v2)
-        .read_from_path(&path)
-    {
-        songname = tags.title().unwrap_or_default().to_owned();
-        artist = tags.artist().unwrap_or_default().to_owned();
-        album = tags.album_title().unwrap_or_default().to_owned();
-    } else {
-        songname = "".to_string();
-        artist = "".to_string();
-        album = "".to_string();
+// Gibt das Album des Songs zurück. Dessen Lautheit muss der Aufrufer danach mit
+// update_album_loudness neu berechnen, einmal pro Album statt pro Track.
+fn add_song_in_transaction(song: &ProbedSong, t: &Connection) -> MyRes<Option<(String, String)>> {
+    println!("add_song_in_transaction({})", song.path);
+    let ProbedSong {
+        path,
+        filename,
+        mtime,
+        size,
+        tags,
+        duration_ms,
+        duration_error,
+        loudness,
+        loudness_error,
+        fingerprint,
+        cover_key,
+    } = song;
+    // Ohne bekannte Länge bleibt seconds NULL, sonst passt der Song auf jeden max_seconds-Filter.
+    let seconds = duration_ms.map(|ms| (ms + 500) / 1000);
+    let length = match seconds {
+        Some(seconds) => format_songlength(seconds),
+        None => GL_UNKNOWN_LENGTH.to_string(),
     };
-
-    let seconds = get_songlength_secs(&path);
-    let length = format_songlength(seconds);
     let rating = GL_RATING_BASE;
     let vote = 0;
     let deleted = 0;
 
+    if let Some(fp) = fingerprint {
+        carry_over_moved_song(path, filename, fp, t)?;
+    }
+
     // result = format!("{result}{path}\n");
     // Statement-Values aufbauen
-    let values = (
-        path, filename, songname, artist, album, length, seconds, rating, vote, deleted,
-    );
+    let values = named_params! {
+        ":path": path,
+        ":filename": filename,
+        ":songname": tags.songname,
+        ":artist": tags.artist,
+        ":album": tags.album,
+        ":length": length,
+        ":seconds": seconds,
+        ":rating": rating,
+        ":vote": vote,
+        ":deleted": deleted,
+        ":mtime": mtime,
+        ":size": size,
+        ":fingerprint": fingerprint,
+        ":album_artist": tags.album_artist,
+        ":track_number": tags.track_number,
+        ":disc_number": tags.disc_number,
+        ":year": tags.year,
+        ":genre": tags.genre,
+        ":composer": tags.composer,
+        ":comment": tags.comment,
+        ":metadata_source": tags.metadata_source,
+        ":cover_key": cover_key,
+        ":track_loudness": loudness.map(|l| l.integrated),
+        ":track_peak": loudness.map(|l| l.peak),
+        ":duration_ms": duration_ms,
+        ":duration_error": duration_error,
+        ":loudness_error": loudness_error,
+    };
+
+    t.prepare_cached(GL_INSERT_SONG_STMT)?.execute(values)?;
 
-    s.execute(values).unwrap();
+    let id = t.query_row("SELECT id FROM songs WHERE path = ?", [path], |row| {
+        row.get::<_, u32>(0)
+    })?;
+    album_key(t, id)
+}
+
+// Ist der Pfad neu und gibt es einen Song mit gleichem Fingerprint, dessen Datei
+// nicht mehr existiert, wurde die Datei verschoben bzw. umbenannt. Dann wird die
+// alte Zeile samt Rating und Playcount auf den neuen Pfad umgehängt.
+fn carry_over_moved_song(
+    path: &str,
+    filename: &str,
+    fingerprint: &str,
+    t: &Connection,
+) -> MyRes<()> {
+    let exists = t
+        .prepare_cached("SELECT count(*) FROM songs WHERE path = ?")?
+        .query_row([path], |row| row.get::<_, u32>(0))?;
+    if exists > 0 {
+        return Ok(());
+    }
+
+    let candidates = t
+        .prepare_cached("SELECT id, path FROM songs WHERE fingerprint = ? ORDER BY deleted, id")?
+        .query_map([fingerprint], |row| {
+            Ok((row.get::<_, u32>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if let Some((id, old)) = candidates
+        .into_iter()
+        .find(|(_, old)| !Path::new(old).exists())
+    {
+        println!("carry_over_moved_song: {id} moved from {old} to {path}");
+        t.prepare_cached("UPDATE songs SET path = ?, filename = ? WHERE id = ?")?
+            .execute((path, filename, id))?;
+    }
+    Ok(())
 }
 
 #[get("/random_id/{scale}")]
-async fn net_get_random_id_with_scale(scale: web::Path<f32>) -> MyRes<String> {
-    println!("net_get_random_id_with_scale({scale})");
+async fn net_get_random_id_with_scale(
+    req: HttpRequest,
+    scale: web::Path<f32>,
+    query: web::Query<ShuffleQuery>,
+) -> MyRes<HttpResponse> {
+    println!("net_get_random_id_with_scale({scale}, {query:?})");
     db_update()?;
-    get_weighted_random_id(*scale)
+    random_id_response(&req, &query, *scale)
 }
 
 #[get("/random_id")]
-async fn net_get_random_id() -> MyRes<String> {
-    println!("net_get_random_id");
+async fn net_get_random_id(
+    req: HttpRequest,
+    query: web::Query<ShuffleQuery>,
+) -> MyRes<HttpResponse> {
+    println!("net_get_random_id({query:?})");
     db_update()?;
-    get_weighted_random_id(GL_DEFAULT_RATING_SCALE)
+    random_id_response(&req, &query, GL_DEFAULT_RATING_SCALE)
 }
 
-#[derive(Serialize)]
+fn random_id_response(req: &HttpRequest, query: &ShuffleQuery, scale: f32) -> MyRes<HttpResponse> {
+    let strategy = match query.strategy(scale) {
+        Ok(s) => s,
+        Err(e) => return Ok(HttpResponse::BadRequest().body(e)),
+    };
+    let (session, cookie) = shuffle_session(req);
+    let Some(id) = get_weighted_random_id(&*strategy, query, &session)? else {
+        return Ok(no_match_response());
+    };
+    let mut res = HttpResponse::Ok();
+    if let Some(cookie) = cookie {
+        res.cookie(cookie);
+    }
+    Ok(res.body(id.to_string()))
+}
+
+fn no_match_response() -> HttpResponse {
+    HttpResponse::NotFound().body("No songs match the filter.")
+}
+
+#[derive(Serialize, Default)]
 struct Song {
     id: i32,
     path: String,
@@ -253,38 +487,115 @@ struct Song {
     artist: String,
     album: String,
     length: String,
-    seconds: i32,
+    seconds: Option<i32>,
     rating: i32,
     vote: i32,
     times_played: i32,
+    album_artist: String,
+    track_number: Option<u32>,
+    disc_number: Option<u32>,
+    year: Option<u32>,
+    genre: String,
+    composer: String,
+    comment: String,
+    metadata_source: String,
+    has_cover: bool,
+    duration_ms: Option<u64>,
+    duration_error: Option<String>,
+}
+
+const GL_SONG_COLUMNS: &str =
+    "id, path, filename, songname, artist, album, length, seconds, rating, vote, times_played,
+    album_artist, track_number, disc_number, year, genre, composer, comment, metadata_source,
+    cover_key is not null, duration_ms, duration_error";
+
+fn song_from_row(row: &rusqlite::Row) -> Result<Song, rusqlite::Error> {
+    Ok(Song {
+        id: row.get::<_, i32>(0)?,
+        path: row.get::<_, String>(1)?,
+        filename: row.get::<_, String>(2)?,
+        songname: row.get::<_, String>(3)?,
+        artist: row.get::<_, String>(4)?,
+        album: row.get::<_, String>(5)?,
+        length: row.get::<_, String>(6)?,
+        seconds: row.get::<_, Option<i32>>(7)?,
+        rating: row.get::<_, i32>(8)?,
+        vote: row.get::<_, i32>(9)?,
+        times_played: row.get::<_, i32>(10)?,
+        album_artist: row.get::<_, Option<String>>(11)?.unwrap_or_default(),
+        track_number: row.get::<_, Option<u32>>(12)?,
+        disc_number: row.get::<_, Option<u32>>(13)?,
+        year: row.get::<_, Option<u32>>(14)?,
+        genre: row.get::<_, Option<String>>(15)?.unwrap_or_default(),
+        composer: row.get::<_, Option<String>>(16)?.unwrap_or_default(),
+        comment: row.get::<_, Option<String>>(17)?.unwrap_or_default(),
+        metadata_source: row.get::<_, Option<String>>(18)?.unwrap_or_default(),
+        has_cover: row.get::<_, bool>(19)?,
+        duration_ms: row.get::<_, Option<u64>>(20)?,
+        duration_error: row.get::<_, Option<String>>(21)?,
+    })
+}
+
+fn get_songlist() -> MyRes<Vec<Song>> {
+    let sql = format!("select {GL_SONG_COLUMNS} from songs where deleted = 0");
+
+    let c = db_con()?;
+    let mut stmt = c.prepare(&sql).wrap_err("prepare")?;
+    let vec = stmt
+        .query_map([], song_from_row)?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(vec)
 }
 
 #[get("/songs")]
 async fn net_songlist() -> MyRes<web::Json<Vec<Song>>> {
     println!("net_songlist");
     db_update()?;
-    let sql = "select id, path, filename, songname, artist, album, length, seconds, rating, vote, times_played from songs where deleted = 0";
+    Ok(Json(get_songlist()?))
+}
 
-    let c = db_con()?;
-    let mut stmt = c.prepare(sql).wrap_err("prepare")?;
-    let vec = stmt.query_map([], |row| {
-        Ok(Song {
-            id: row.get::<_, i32>(0)?,
-            path: row.get::<_, String>(1)?,
-            filename: row.get::<_, String>(2)?,
-            songname: row.get::<_, String>(3)?,
-            artist: row.get::<_, String>(4)?,
-            album: row.get::<_, String>(5)?,
-            length: row.get::<_, String>(6)?,
-            seconds: row.get::<_, i32>(7)?,
-            rating: row.get::<_, i32>(8)?,
-            vote: row.get::<_, i32>(9)?,
-            times_played: row.get::<_, i32>(10)?,
-        })
-    });
+#[derive(Serialize)]
+struct LoudnessError {
+    id: u32,
+    path: String,
+    loudness_error: String,
+}
 
-    let vec = vec?.into_iter().collect::<Result<Vec<_>, _>>()?;
+// Songs, deren Lautheit weder symphonia noch ffmpeg messen konnte.
+#[get("/loudness_errors")]
+async fn net_loudness_errors() -> MyRes<web::Json<Vec<LoudnessError>>> {
+    println!("net_loudness_errors");
+    db_update()?;
+    let c = db_con()?;
+    let mut stmt = c.prepare(
+        "select id, path, loudness_error from songs
+        where deleted = 0 and loudness_error is not null order by path",
+    )?;
+    let vec = stmt
+        .query_map([], |row| {
+            Ok(LoudnessError {
+                id: row.get(0)?,
+                path: row.get(1)?,
+                loudness_error: row.get(2)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Json(vec))
+}
 
+// Songs, deren Länge beim Scannen nicht bestimmt werden konnte, mit Grund.
+#[get("/duration_errors")]
+async fn net_duration_errors() -> MyRes<web::Json<Vec<Song>>> {
+    println!("net_duration_errors");
+    db_update()?;
+    let sql = format!(
+        "select {GL_SONG_COLUMNS} from songs where deleted = 0 and duration_ms is null order by path"
+    );
+    let c = db_con()?;
+    let mut stmt = c.prepare(&sql).wrap_err("prepare")?;
+    let vec = stmt
+        .query_map([], song_from_row)?
+        .collect::<Result<Vec<_>, _>>()?;
     Ok(Json(vec))
 }
 
@@ -292,58 +603,74 @@ async fn net_songlist() -> MyRes<web::Json<Vec<Song>>> {
 async fn net_songlist_web(app: Data<AppState>) -> MyRes<HttpResponse> {
     println!("net_songlist_web");
     db_update()?;
-    let sql = "select id, path, filename, songname, artist, album, length, seconds, rating, vote, times_played from songs where deleted = 0";
-
-    let c = db_con()?;
-    let mut stmt = c.prepare(sql).wrap_err("prepare")?;
-    let vec = stmt
-        .query_map([], |row| {
-            Ok(Song {
-                id: row.get::<_, i32>(0)?,
-                path: row.get::<_, String>(1)?,
-                filename: row.get::<_, String>(2)?,
-                songname: row.get::<_, String>(3)?,
-                artist: row.get::<_, String>(4)?,
-                album: row.get::<_, String>(5)?,
-                length: row.get::<_, String>(6)?,
-                seconds: row.get::<_, i32>(7)?,
-                rating: row.get::<_, i32>(8)?,
-                vote: row.get::<_, i32>(9)?,
-                times_played: row.get::<_, i32>(10)?,
-            })
-        })
-        .unwrap()
-        .collect::<Result<Vec<_>, _>>()?;
-    // vec.iter().for_each(|s| {
-    //     println!(
-    //         "Song: {} - {} - {} - {} - {}",
-    //         s.id, s.songname, s.artist, s.album, s.length
-    //     );
-    // });
+    let vec = get_songlist()?;
     let rendered = app.render_template("songlist.html", context! {songs => &vec})?;
     Ok(HttpResponse::Ok().body(rendered))
 }
 
-#[get("/songs/{id}")]
-async fn net_song_by_id(id: web::Path<u32>) -> MyRes<NamedFile> {
+#[route(
+    "/songs/{id}",
+    method = "GET",
+    method = "HEAD",
+    wrap = "from_fn(song_ranges)"
+)]
+async fn net_song_by_id(
+    req: HttpRequest,
+    id: web::Path<u32>,
+    query: web::Query<TranscodeQuery>,
+) -> MyRes<HttpResponse> {
     println!("net_song_by_id({id})");
     db_update()?;
     let id = id.into_inner();
-    increase_times_played(id)?;
+    let target = match query.target(id) {
+        Ok(t) => t,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().body(format!(
+                "{e}. Supported: {}",
+                supported_formats().join(", ")
+            )))
+        }
+    };
     let val = get_songpath_by_id(id)?;
-    get_file_by_name(&val)
+    let res = match target {
+        None => get_file_by_name(&val)?.into_response(&req),
+        Some(target) => match cached_transcode(Path::new(&val), &target) {
+            Some(transcoded) => get_transcoded_file(&val, &transcoded)?.into_response(&req),
+            None => transcoded_stream_response(&val, &target).await?,
+        },
+    };
+    // Ob der Play gezählt werden kann, entscheidet nicht darüber, ob der Song spielt.
+    if let Err(e) = count_play(&req, &res, id, "stream") {
+        println!("net_song_by_id: could not count play of {id}: {e}");
+    }
+    Ok(res)
 }
 
+// Leitet auf /songs/{id} weiter, damit Range-Requests beim Spulen denselben Song treffen.
 #[get("/songs/random")]
-async fn net_song_random() -> MyRes<NamedFile> {
-    println!("net_song_random");
+async fn net_song_random(req: HttpRequest, query: web::Query<ShuffleQuery>) -> MyRes<HttpResponse> {
+    println!("net_song_random({query:?})");
     db_update()?;
-    let id = get_weighted_random_id(GL_DEFAULT_RATING_SCALE)?
-        .parse::<u32>()
-        .unwrap_or_default();
-    let path = get_songpath_by_id(id)?;
-    increase_times_played(id)?;
-    get_file_by_name(&path)
+    let strategy = match query.strategy(GL_DEFAULT_RATING_SCALE) {
+        Ok(s) => s,
+        Err(e) => return Ok(HttpResponse::BadRequest().body(e)),
+    };
+    let (session, cookie) = shuffle_session(&req);
+    let Some(id) = get_weighted_random_id(&*strategy, &query, &session)? else {
+        return Ok(no_match_response());
+    };
+    let mut location = format!("/songs/{id}");
+    if !req.query_string().is_empty() {
+        location = format!("{location}?{}", req.query_string());
+    }
+    let mut res = HttpResponse::Found();
+    if let Some(cookie) = cookie {
+        res.cookie(cookie);
+    }
+    Ok(res
+        .insert_header((LOCATION, location))
+        .insert_header((CACHE_CONTROL, "no-store"))
+        .finish())
 }
 
 #[get("/songdata/{id}")]
@@ -367,21 +694,41 @@ async fn net_songdata_pretty_by_id(id: web::Path<u32>) -> MyRes<String> {
 fn get_songdata_json(id: u32) -> MyRes<JsonValue> {
     println!("get_songdata_json");
     db_select(
-        "select * from songs where id = ?",
+        &format!("select {GL_SONG_COLUMNS}, track_loudness, track_peak, album_loudness, album_peak from songs where id = ?"),
         [id],
         |row| -> Result<json::JsonValue, rusqlite::Error> {
+            let s = song_from_row(row)?;
+            let track = loudness_from_row(row, "track_loudness", "track_peak")?;
+            let album = loudness_from_row(row, "album_loudness", "album_peak")?;
             Ok(object! {
-                id: row.get(0).unwrap_or(0),
-                path: row.get(1).unwrap_or_else(|_a| "".to_string()),
-                filename: row.get(2).unwrap_or_else(|_a| "".to_string()),
-                songname: row.get(3).unwrap_or_else(|_a| "".to_string()),
-                artist: row.get(4).unwrap_or_else(|_a| "".to_string()),
-                album: row.get(5).unwrap_or_else(|_a| "".to_string()),
-                length: row.get(6).unwrap_or_else(|_a| "".to_string()),
-                seconds: row.get(7).unwrap_or(0),
-                rating: row.get(8).unwrap_or(0),
-                vote: row.get(9).unwrap_or(0),
-                times_played: row.get(11).unwrap_or(0)
+                id: s.id,
+                path: s.path,
+                filename: s.filename,
+                songname: s.songname,
+                artist: s.artist,
+                album: s.album,
+                length: s.length,
+                seconds: s.seconds,
+                rating: s.rating,
+                vote: s.vote,
+                times_played: s.times_played,
+                album_artist: s.album_artist,
+                track_number: s.track_number,
+                disc_number: s.disc_number,
+                year: s.year,
+                genre: s.genre,
+                composer: s.composer,
+                comment: s.comment,
+                metadata_source: s.metadata_source,
+                has_cover: s.has_cover,
+                duration_ms: s.duration_ms,
+                duration_error: s.duration_error,
+                track_loudness: track.map(|l| l.integrated),
+                track_peak: track.map(|l| l.peak),
+                album_loudness: album.map(|l| l.integrated),
+                album_peak: album.map(|l| l.peak),
+                replaygain_track_gain: track.map(replay_gain),
+                replaygain_album_gain: album.map(replay_gain)
             })
         },
     )
@@ -392,12 +739,7 @@ async fn net_song_upvote_by_id(id: web::Path<u32>) -> MyRes<String> {
     println!("net_song_upvote_by_id({id})");
     db_update()?;
     let id = id.into_inner();
-    let mut val = adb_uint32_read(&format!("SELECT rating FROM songs WHERE id = {id}"))?;
-    if val < 7 {
-        val += 1;
-        let i = "Update songs set rating = ? where id = ?";
-        db_execute(i, (val, id))?;
-    }
+    let val = change_rating(id, 1)?;
     Ok(format!("Upvoted {id}. New Score: {val}"))
 }
 
@@ -406,13 +748,69 @@ async fn net_song_downvote_by_id(id: web::Path<u32>) -> MyRes<String> {
     println!("net_song_downvote_by_id({id})");
     db_update()?;
     let id = id.into_inner();
-    let mut val = adb_uint32_read(&format!("SELECT rating FROM songs WHERE id = {id}"))?;
-    if val > 0 {
-        val -= 1;
+    let val = change_rating(id, -1)?;
+    Ok(format!("Downvoted {id}. New Score: {val}"))
+}
+
+#[get("/like/{id}")]
+async fn net_song_like_by_id(id: web::Path<u32>) -> MyRes<HttpResponse> {
+    println!("net_song_like_by_id({id})");
+    db_update()?;
+    let id = id.into_inner();
+    Ok(match change_vote(id, 1)? {
+        Some(val) => HttpResponse::Ok().body(format!("Liked {id}. New Vote: {val}")),
+        None => song_not_found(id),
+    })
+}
+
+#[get("/dislike/{id}")]
+async fn net_song_dislike_by_id(id: web::Path<u32>) -> MyRes<HttpResponse> {
+    println!("net_song_dislike_by_id({id})");
+    db_update()?;
+    let id = id.into_inner();
+    Ok(match change_vote(id, -1)? {
+        Some(val) => HttpResponse::Ok().body(format!("Disliked {id}. New Vote: {val}")),
+        None => song_not_found(id),
+    })
+}
+
+#[get("/resetvote/{id}")]
+async fn net_song_resetvote_by_id(id: web::Path<u32>) -> MyRes<HttpResponse> {
+    println!("net_song_resetvote_by_id({id})");
+    db_update()?;
+    let id = id.into_inner();
+    let changed = db_con()?.execute("Update songs set vote = 0 where id = ?", [id])?;
+    if changed == 0 {
+        return Ok(song_not_found(id));
+    }
+    Ok(HttpResponse::Ok().body(format!("Reset vote of {id}.")))
+}
+
+fn song_not_found(id: u32) -> HttpResponse {
+    HttpResponse::NotFound().body(format!("No song with id {id}."))
+}
+
+// Likes und Dislikes summieren sich unabhängig vom Rating. Gibt den neuen Wert
+// zurück, None für eine unbekannte id.
+fn change_vote(id: u32, delta: i32) -> MyRes<Option<i32>> {
+    Ok(db_con()?
+        .query_row(
+            "Update songs set vote = coalesce(vote, 0) + ? where id = ? returning vote",
+            (delta, id),
+            |row| row.get::<_, i32>(0),
+        )
+        .optional()?)
+}
+
+// Ändert das Rating um delta, begrenzt auf 0..=7. Gibt das neue Rating zurück.
+pub fn change_rating(id: u32, delta: i32) -> MyRes<u32> {
+    let val = adb_uint32_read(&format!("SELECT rating FROM songs WHERE id = {id}"))?;
+    let new = (val as i32 + delta).clamp(0, 7) as u32;
+    if new != val {
         let i = "Update songs set rating = ? where id = ?";
-        db_execute(i, (val, id))?;
+        db_execute(i, (new, id))?;
     }
-    Ok(format!("Downvoted {id}. New Score: {val}"))
+    Ok(new)
 }
 
 #[get("/*")]
@@ -421,37 +819,25 @@ async fn net_404() -> MyRes<String> {
     Err(ErrorNotFound("No pages here.").into())
 }
 
-fn get_songpath_by_id(id: u32) -> MyRes<String> {
+pub fn get_songpath_by_id(id: u32) -> MyRes<String> {
     println!("get_songpath_by_id({id})");
     let i = &format!("SELECT path FROM songs WHERE id = {id}");
     db_str_read(i)
 }
 
-fn get_songlength_secs(path: &str) -> u64 {
-    let path = Path::new(path);
-    let duration = mp3_duration::from_path(path).unwrap_or_default();
-    duration.as_secs()
-}
-
-fn increase_times_played(id: u32) -> MyRes<()> {
-    println!("increase_times_played({id})");
-    let i = "Update songs set times_played = times_played + 1 where id = ?";
-    db_execute(i, [id])
-}
-
 fn format_songlength(seconds: u64) -> String {
     let mins = seconds / 60;
     let secs = seconds % 60;
     if mins >= 60 {
         let hours = mins / 60;
-        let mins = mins / 60;
+        let mins = mins % 60;
         format!("{hours}:{mins:0>2}:{secs:0>2}")
     } else {
         format!("{mins:0>1}:{secs:0>2}")
     }
 }
 
-fn get_file_by_name(path: &str) -> MyRes<NamedFile> {
+pub fn get_file_by_name(path: &str) -> MyRes<NamedFile> {
     println!("get_file_by_name");
     let p = Path::new(path);
 
@@ -464,6 +850,8 @@ fn get_file_by_name(path: &str) -> MyRes<NamedFile> {
         .unwrap_or_default();
 
     Ok(file
+        .set_content_type(get_mime_type(p).parse()?)
+        .use_etag(true)
         .use_last_modified(true)
         .set_content_disposition(ContentDisposition {
             disposition: DispositionType::Inline,
@@ -471,49 +859,118 @@ fn get_file_by_name(path: &str) -> MyRes<NamedFile> {
         }))
 }
 
-fn get_weighted_random_id(scale: f32) -> MyRes<String> {
-    println!("get_weighted_random_id");
-    let c = db_con()?;
+fn transcoded_disposition(original: &str, extension: &str) -> ContentDisposition {
+    let filename = Path::new(original)
+        .with_extension(extension)
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+    ContentDisposition {
+        disposition: DispositionType::Inline,
+        parameters: vec![DispositionParam::Filename(filename)],
+    }
+}
 
-    let mut stmt = c.prepare("select rating, id from songs where deleted = 0 and rating > 0")?;
+// Wie get_file_by_name, aber mit dem Dateinamen des Originals.
+pub fn get_transcoded_file(original: &str, transcoded: &Path) -> MyRes<NamedFile> {
+    let file = get_file_by_name(&transcoded.to_string_lossy())?;
+    let extension = transcoded.extension().unwrap_or_default().to_string_lossy();
+    Ok(file.set_content_disposition(transcoded_disposition(original, &extension)))
+}
 
-    let rows = stmt.query_map([], |row| -> Result<(u32, i32), rusqlite::Error> {
-        Ok((row.get::<usize, u32>(0)?, row.get::<usize, i32>(1)?))
-    })?;
+// Noch nicht im Cache: ffmpeg-Ausgabe direkt durchreichen, ohne Länge und ohne Ranges.
+// Schlägt ffmpeg vor dem ersten Chunk fehl, gibt es noch einen richtigen Fehler.
+async fn transcoded_stream_response(original: &str, target: &Transcode) -> MyRes<HttpResponse> {
+    let mut rx = stream_transcoded(Path::new(original), target)?;
+    let first = match rx.recv().await {
+        Some(Ok(chunk)) => chunk,
+        Some(Err(e)) => return Err(e.into()),
+        None => return Err("ffmpeg produced no output".into()),
+    };
 
-    let map = rows
-        .into_iter()
-        .collect::<Result<Vec<(u32, i32)>, _>>()?
-        .into_iter()
-        .map(|a| {
-            let rating = scale.powi((a.0 - 1) as i32).round() as u32;
-            (rating, a.1)
-        })
-        .collect::<Vec<(u32, i32)>>();
+    let stream = futures_util::stream::once(async move { Ok(first) }).chain(
+        futures_util::stream::unfold(rx, |mut rx| async move {
+            let chunk = rx.recv().await?.map_err(ErrorInternalServerError);
+            Some((chunk, rx))
+        }),
+    );
+    let extension = target.codec.extension;
+    Ok(HttpResponse::Ok()
+        .content_type(get_mime_type(
+            &Path::new(original).with_extension(extension),
+        ))
+        .insert_header(transcoded_disposition(original, extension))
+        .streaming(stream))
+}
 
-    let mut c: i32;
+// Jede Shuffle-Session hat ihre eigene Replay-Protection. None, wenn kein Song
+// auf den Filter passt.
+fn get_weighted_random_id(
+    strategy: &dyn ShuffleStrategy,
+    filter: &ShuffleQuery,
+    session: &str,
+) -> MyRes<Option<u32>> {
+    println!("get_weighted_random_id");
+    let c = db_con()?;
 
-    let lasts = LAST_SONGS.clone();
-    let Ok(mut inner) = lasts.lock() else {
-        Err(eyre!("Could not acquire mutex!"))?;
-        unreachable!();
+    let mut stmt = c.prepare(
+        "select id, rating, coalesce(vote, 0), times_played,
+        (strftime('%s', 'now') - (select max(played_at) from plays where song_id = songs.id)) / 86400.0,
+        artist, album, coalesce(nullif(album_artist, ''), artist)
+        from songs where deleted = 0 and merged_into is null and rating > 0
+        and (:artist is null or artist = :artist collate nocase)
+        and (:album is null or album = :album collate nocase)
+        and (:genre is null or genre = :genre collate nocase)
+        and (:folder is null or substr(path, 1, length(:folder)) = :folder)
+        and (:min_rating is null or rating >= :min_rating)
+        and (:max_rating is null or rating <= :max_rating)
+        and (:min_seconds is null or seconds >= :min_seconds)
+        and (:max_seconds is null or seconds <= :max_seconds)",
+    )?;
+
+    let params = named_params! {
+        ":artist": filter.artist,
+        ":album": filter.album,
+        ":genre": filter.genre,
+        ":folder": filter.folder_prefix(),
+        ":min_rating": filter.min_rating,
+        ":max_rating": filter.max_rating,
+        ":min_seconds": filter.min_seconds,
+        ":max_seconds": filter.max_seconds,
     };
-    loop {
-        c = rng(&map)?;
+    let rows = stmt.query_map(params, |row| -> Result<Candidate, rusqlite::Error> {
+        Ok(Candidate {
+            id: row.get(0)?,
+            rating: row.get(1)?,
+            vote: row.get(2)?,
+            times_played: row.get::<_, Option<u32>>(3)?.unwrap_or_default(),
+            days_since_played: row.get::<_, Option<f64>>(4)?.map(|d| d as f32),
+            artist: row.get::<_, Option<String>>(5)?.unwrap_or_default(),
+            album: row.get::<_, Option<String>>(6)?.unwrap_or_default(),
+            album_artist: row.get::<_, Option<String>>(7)?.unwrap_or_default(),
+        })
+    })?;
 
-        if !inner.contains(&c) {
-            if inner.len() >= GL_REPLAY_PROTECTION {
-                inner.remove(0);
-            }
-            inner.push(c);
-            break;
-        } else {
-            println!("{c} ist schon in der Liste!");
-        }
+    let songs = rows
+        .collect::<Result<Vec<Candidate>, _>>()?
+        .into_iter()
+        .map(|song| (strategy.weight(&song), song))
+        .collect::<Vec<(u32, Candidate)>>();
+    if songs.is_empty() {
+        return Ok(None);
     }
-    drop(inner);
 
-    Ok(c.to_string())
+    let history = session_history(session)?;
+    let windows = ReplayWindows {
+        songs: GL_REPLAY_PROTECTION,
+        artists: *GL_ARTIST_REPLAY_PROTECTION,
+        albums: *GL_ALBUM_REPLAY_PROTECTION,
+    };
+    let c = pick_with_spread(&songs, &history, windows)?;
+    push_history(session, c)?;
+
+    Ok(Some(c as u32))
 }
 
 pub fn rng(map: &[(u32, i32)]) -> MyRes<i32> {
@@ -554,15 +1011,27 @@ async fn net_upload(mut payload: Multipart) -> HttpResponse {
                     .body(format!("Failed to update database: {}", e));
             }
 
-            let Ok(mut db) = db_con() else {
-                return HttpResponse::InternalServerError().body("Failed to connect to database");
-            };
-
-            let t = db.transaction().unwrap();
-            let mut s = t.prepare(GL_INSERT_SONG_STMT).unwrap();
-            add_song_in_transaction(filepath.to_str().unwrap(), &filename, &mut s);
-            drop(s);
-            t.commit().unwrap();
+            // Lautheit und Fingerprint dekodieren die ganze Datei, das gehört nicht auf
+            // den Worker-Thread.
+            let res = web::block(move || -> Result<(), String> {
+                let (mtime, size) = file_stamp_by_path(&filepath);
+                let song = probe_song(filepath.to_str().unwrap(), &filename, mtime, size);
+                let mut db = db_con().map_err(|_| "Failed to connect to database".to_string())?;
+                let t = db.transaction().map_err(|e| e.to_string())?;
+                add_song_in_transaction(&song, &t)
+                    .and_then(|album| match album {
+                        Some(album) => update_album_loudness(&t, &album),
+                        None => Ok(()),
+                    })
+                    .map_err(|e| format!("Failed to add song: {e}"))?;
+                t.commit().map_err(|e| e.to_string())
+            })
+            .await;
+            match res {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => return HttpResponse::InternalServerError().body(e),
+                Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+            }
         }
     }
 
@@ -575,30 +1044,584 @@ struct UpdateSongData {
     artist: String,
     album: String,
     rating: u8,
+    // Fehlt ein Feld, bleibt der Wert in der DB unverändert.
+    album_artist: Option<String>,
+    track_number: Option<u32>,
+    disc_number: Option<u32>,
+    year: Option<u32>,
+    genre: Option<String>,
+    composer: Option<String>,
+    comment: Option<String>,
+    // Änderungen auch in die Tags der Datei schreiben. Sonst bleiben sie nur in der
+    // DB und werden beim Rescan nicht überschrieben.
+    #[serde(default)]
+    write_tags: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct UpdateSongDataWithId {
+    id: u32,
+    #[serde(flatten)]
+    data: UpdateSongData,
+}
+
+// Im UPDATE sehen die Spalten noch die alten Werte.
+const GL_TAGS_CHANGED_SQL: &str = "(coalesce(songname, '') IS NOT :songname
+    OR coalesce(artist, '') IS NOT :artist
+    OR coalesce(album, '') IS NOT :album
+    OR (:album_artist IS NOT NULL AND coalesce(album_artist, '') IS NOT :album_artist)
+    OR (:track_number IS NOT NULL AND track_number IS NOT :track_number)
+    OR (:disc_number IS NOT NULL AND disc_number IS NOT :disc_number)
+    OR (:year IS NOT NULL AND year IS NOT :year)
+    OR (:genre IS NOT NULL AND coalesce(genre, '') IS NOT :genre)
+    OR (:composer IS NOT NULL AND coalesce(composer, '') IS NOT :composer)
+    OR (:comment IS NOT NULL AND coalesce(comment, '') IS NOT :comment))";
+
+#[derive(Serialize, Debug)]
+struct SongUpdateResult {
+    id: u32,
+    db_updated: bool,
+    tags_written: Option<bool>,
+    error: Option<String>,
+}
+
+fn update_songdata(id: u32, d: UpdateSongData) -> SongUpdateResult {
+    let mut res = SongUpdateResult {
+        id,
+        db_updated: false,
+        tags_written: None,
+        error: None,
+    };
+
+    // Nur wenn sich ein Tag wirklich ändert, gilt der Song als manuell gepflegt und
+    // wird beim Rescan nicht mehr aus der Datei überschrieben. Eine reine
+    // Rating-Änderung lässt Sperre und Herkunft der Tags in Ruhe.
+    let sql = format!(
+        "UPDATE songs SET songname = :songname, artist = :artist, album = :album, rating = :rating,
+        album_artist = coalesce(:album_artist, album_artist),
+        track_number = coalesce(:track_number, track_number),
+        disc_number = coalesce(:disc_number, disc_number),
+        year = coalesce(:year, year),
+        genre = coalesce(:genre, genre),
+        composer = coalesce(:composer, composer),
+        comment = coalesce(:comment, comment),
+        metadata_source = iif({GL_TAGS_CHANGED_SQL}, 'manual', metadata_source),
+        tags_locked = iif({GL_TAGS_CHANGED_SQL}, 1, tags_locked)
+        WHERE id = :id"
+    );
+
+    let old_album = db_con().and_then(|c| album_key(&c, id)).ok().flatten();
+
+    let updated = db_con().and_then(|c| {
+        Ok(c.execute(
+            &sql,
+            named_params! {
+                ":songname": d.songname,
+                ":artist": d.artist,
+                ":album": d.album,
+                ":rating": d.rating,
+                ":album_artist": d.album_artist,
+                ":track_number": d.track_number,
+                ":disc_number": d.disc_number,
+                ":year": d.year,
+                ":genre": d.genre,
+                ":composer": d.composer,
+                ":comment": d.comment,
+                ":id": id,
+            },
+        )?)
+    });
+    match updated {
+        Ok(0) => {
+            res.error = Some(format!("Song {id} not found"));
+            return res;
+        }
+        Ok(_) => {}
+        Err(e) => {
+            res.error = Some(e.to_string());
+            return res;
+        }
+    }
+    res.db_updated = true;
+
+    // Album kann sich geändert haben, dann stimmen die Albumwerte beider Alben nicht mehr.
+    let albums = db_con().and_then(|c| {
+        let new_album = album_key(&c, id)?;
+        for album in old_album.iter().chain(new_album.iter()) {
+            update_album_loudness(&c, album)?;
+        }
+        Ok(())
+    });
+    if let Err(e) = albums {
+        println!("update_songdata: album loudness for {id}: {e}");
+    }
+
+    if d.write_tags {
+        let written = write_song_tags(id);
+        res.tags_written = Some(written.is_ok());
+        res.error = written.err().map(|e| e.to_string());
+    }
+    res
+}
+
+// Schreibt die Werte aus der DB in die Datei. Danach stimmen Datei und DB überein,
+// die Sperre gegen das Überschreiben beim Rescan kann also weg.
+fn write_song_tags(id: u32) -> MyRes<()> {
+    let song = db_select(
+        &format!("select {GL_SONG_COLUMNS} from songs where id = ?"),
+        [id],
+        song_from_row,
+    )?;
+    let path = Path::new(&song.path);
+    write_tags(
+        path,
+        &SongTags {
+            songname: song.songname,
+            artist: song.artist,
+            album: song.album,
+            album_artist: song.album_artist,
+            track_number: song.track_number,
+            disc_number: song.disc_number,
+            year: song.year,
+            genre: song.genre,
+            composer: song.composer,
+            comment: song.comment,
+            metadata_source: song.metadata_source,
+        },
+    )?;
+    let (mtime, size) = file_stamp_by_path(path);
+    db_execute(
+        "UPDATE songs SET tags_locked = 0, mtime = ?, size = ? WHERE id = ?",
+        (mtime, size, id),
+    )
 }
 
 #[post("/songdata/{id}")]
 async fn net_update_songdata_by_id_post(
     id: web::Path<u32>,
     data: Json<UpdateSongData>,
-) -> MyRes<String> {
+) -> MyRes<Json<SongUpdateResult>> {
     println!("net_songdata_by_id_post({id})");
     db_update()?;
-    let d = data.into_inner();
-    let id = id.into_inner();
+    Ok(Json(update_songdata(id.into_inner(), data.into_inner())))
+}
 
-    let sql = "UPDATE songs SET songname = ?, artist = ?, album = ?, rating = ? WHERE id = ?";
+#[post("/songdata")]
+async fn net_update_songdata_post(
+    data: Json<Vec<UpdateSongDataWithId>>,
+) -> MyRes<Json<Vec<SongUpdateResult>>> {
+    println!("net_update_songdata_post({})", data.len());
+    db_update()?;
+    Ok(Json(
+        data.into_inner()
+            .into_iter()
+            .map(|d| update_songdata(d.id, d.data))
+            .collect(),
+    ))
+}
 
-    db_execute(&sql, (&d.songname, &d.artist, &d.album, &d.rating, &id))?;
+#[derive(Deserialize, Debug)]
+struct WriteTagsRequest {
+    ids: Vec<u32>,
+}
 
-    Ok(format!("Updated song with ID: {id}"))
+#[post("/write_tags")]
+async fn net_write_tags(data: Json<WriteTagsRequest>) -> MyRes<Json<Vec<SongUpdateResult>>> {
+    println!("net_write_tags({data:?})");
+    db_update()?;
+    Ok(Json(
+        data.ids
+            .iter()
+            .map(|&id| {
+                let written = write_song_tags(id);
+                SongUpdateResult {
+                    id,
+                    db_updated: false,
+                    tags_written: Some(written.is_ok()),
+                    error: written.err().map(|e| e.to_string()),
+                }
+            })
+            .collect(),
+    ))
 }
 
 #[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
+    use std::{collections::HashMap, env, path::PathBuf, sync::Once};
+
+    use actix_web::{
+        http::{header, StatusCode},
+        test as web_test, App,
+    };
+
+    use crate::{
+        add_song_in_transaction, db::db_con, db::db_select, db::db_uint32_read, net_get_random_id,
+        net_song_by_id, net_song_like_by_id, net_song_random, net_song_resetvote_by_id,
+        plays::net_skip, probe_song, rng, scanner::file_stamp_by_path, update_album_loudness,
+        update_manager::db_update, update_songdata, UpdateSongData, GL_MUSICDIR,
+    };
+
+    static SETUP: Once = Once::new();
+
+    // Eigene Datenbank im Temp-Verzeichnis mit den beiden Songs aus music/.
+    fn setup() {
+        SETUP.call_once(|| {
+            let dir = env::temp_dir().join(format!("music-srv-test-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            env::set_var("DBDIR", &dir);
+            env::set_var("CACHEDIR", dir.join("cache"));
+            db_update().unwrap();
+
+            let songs = ["gardens-stylish-chill-303261.mp3", "titanium-170190.mp3"].map(|name| {
+                let path = GL_MUSICDIR.join(name);
+                let (mtime, size) = file_stamp_by_path(&path);
+                probe_song(path.to_str().unwrap(), name, mtime, size)
+            });
+            let mut c = db_con().unwrap();
+            let t = c.transaction().unwrap();
+            for song in &songs {
+                if let Some(album) = add_song_in_transaction(song, &t).unwrap() {
+                    update_album_loudness(&t, &album).unwrap();
+                }
+            }
+            t.commit().unwrap();
+        });
+    }
+
+    fn song_id(name: &str) -> u32 {
+        db_uint32_read(&format!("SELECT id FROM songs WHERE filename = '{name}'")).unwrap()
+    }
+
+    fn times_played(id: u32) -> u32 {
+        db_uint32_read(&format!("SELECT times_played FROM songs WHERE id = {id}")).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn test_song_range_and_conditional() {
+        setup();
+        let id = song_id("gardens-stylish-chill-303261.mp3");
+        let size = std::fs::metadata(
+            PathBuf::from(&*GL_MUSICDIR).join("gardens-stylish-chill-303261.mp3"),
+        )
+        .unwrap()
+        .len();
+        let app = web_test::init_service(App::new().service(net_song_by_id)).await;
+        let uri = format!("/songs/{id}");
+        let before = times_played(id);
+
+        let res =
+            web_test::call_service(&app, web_test::TestRequest::get().uri(&uri).to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get(header::ACCEPT_RANGES).unwrap(), "bytes");
+        let etag = res.headers().get(header::ETAG).unwrap().clone();
+        let modified = res.headers().get(header::LAST_MODIFIED).unwrap().clone();
+
+        let req = web_test::TestRequest::get()
+            .uri(&uri)
+            .insert_header((header::RANGE, "bytes=0-99"))
+            .to_request();
+        let res = web_test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            res.headers()
+                .get(header::CONTENT_RANGE)
+                .unwrap()
+                .to_str()
+                .unwrap(),
+            format!("bytes 0-99/{size}")
+        );
+        assert_eq!(web_test::read_body(res).await.len(), 100);
+
+        // If-Range mit passendem ETag liefert den Ausschnitt, sonst die ganze Datei.
+        let req = web_test::TestRequest::get()
+            .uri(&uri)
+            .insert_header((header::RANGE, "bytes=100-199"))
+            .insert_header((header::IF_RANGE, etag.clone()))
+            .to_request();
+        let res = web_test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+
+        let req = web_test::TestRequest::get()
+            .uri(&uri)
+            .insert_header((header::RANGE, "bytes=100-199"))
+            .insert_header((header::IF_RANGE, modified))
+            .to_request();
+        let res = web_test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+
+        let req = web_test::TestRequest::get()
+            .uri(&uri)
+            .insert_header((header::RANGE, "bytes=100-199"))
+            .insert_header((header::IF_RANGE, "\"outdated\""))
+            .to_request();
+        let res = web_test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(web_test::read_body(res).await.len() as u64, size);
+
+        let req = web_test::TestRequest::get()
+            .uri(&uri)
+            .insert_header((header::IF_NONE_MATCH, etag))
+            .to_request();
+        let res = web_test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        // Nur das erste GET war eine Wiedergabe.
+        assert_eq!(times_played(id), before + 1);
+    }
+
+    #[actix_web::test]
+    async fn test_seeking_counts_one_play() {
+        setup();
+        let id = song_id("titanium-170190.mp3");
+        let before = times_played(id);
+        let app = web_test::init_service(App::new().service(net_song_by_id)).await;
+        let uri = format!("/songs/{id}");
+
+        let req = web_test::TestRequest::default()
+            .method(actix_web::http::Method::HEAD)
+            .uri(&uri)
+            .to_request();
+        assert_eq!(
+            web_test::call_service(&app, req).await.status(),
+            StatusCode::OK
+        );
+        assert_eq!(times_played(id), before);
+
+        for range in [
+            "bytes=0-",
+            "bytes=50000-",
+            "bytes=0-1023",
+            "bytes=120000-130000",
+        ] {
+            let req = web_test::TestRequest::get()
+                .uri(&uri)
+                .insert_header((header::RANGE, range))
+                .to_request();
+            let res = web_test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        }
+        assert_eq!(times_played(id), before + 1);
+        let history = db_uint32_read(&format!(
+            "SELECT count(*) FROM plays WHERE song_id = {id} AND source = 'stream'"
+        ))
+        .unwrap();
+        assert_eq!(history, before + 1);
+    }
+
+    #[actix_web::test]
+    async fn test_vote_unknown_song() {
+        setup();
+        let app = web_test::init_service(
+            App::new()
+                .service(net_song_like_by_id)
+                .service(net_song_resetvote_by_id),
+        )
+        .await;
+        for uri in ["/like/999999", "/resetvote/999999"] {
+            let req = web_test::TestRequest::get().uri(uri).to_request();
+            let res = web_test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[test]
+    fn test_rating_only_edit_keeps_tags_unlocked() {
+        setup();
+        let id = song_id("gardens-stylish-chill-303261.mp3");
+        let read = || {
+            db_select(
+                "SELECT songname, artist, album, metadata_source, tags_locked FROM songs WHERE id = ?",
+                [id],
+                |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, String>(3)?,
+                        row.get::<_, bool>(4)?,
+                    ))
+                },
+            )
+            .unwrap()
+        };
+        let (songname, artist, album, source, locked) = read();
+        assert!(!locked);
+        let edit = |songname: &str, rating| UpdateSongData {
+            songname: songname.to_string(),
+            artist: artist.clone(),
+            album: album.clone(),
+            rating,
+            album_artist: None,
+            track_number: None,
+            disc_number: None,
+            year: None,
+            genre: None,
+            composer: None,
+            comment: None,
+            write_tags: false,
+        };
+
+        assert!(update_songdata(id, edit(&songname, 5)).db_updated);
+        let after = read();
+        assert_eq!((after.3.as_str(), after.4), (source.as_str(), false));
+
+        assert!(update_songdata(id, edit("Renamed", 5)).db_updated);
+        let after = read();
+        assert_eq!((after.3.as_str(), after.4), ("manual", true));
+    }
+
+    #[test]
+    fn test_carry_over_moved_song() {
+        setup();
+        let dir = env::temp_dir().join(format!("music-srv-moved-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let original = GL_MUSICDIR.join("gardens-stylish-chill-303261.mp3");
+        let probe = |name: &str| {
+            let path = dir.join(name);
+            std::fs::copy(&original, &path).unwrap();
+            let (mtime, size) = file_stamp_by_path(&path);
+            probe_song(path.to_str().unwrap(), name, mtime, size)
+        };
+        let moved = probe("moved.mp3");
+        let copy = probe("copy.mp3");
+        let fingerprint = moved.fingerprint.clone().unwrap();
+
+        // Alles in einer Transaktion, die am Ende verworfen wird, damit parallele
+        // Tests die Zeilen nie sehen.
+        let mut c = db_con().unwrap();
+        let t = c.transaction().unwrap();
+        t.execute(
+            "INSERT INTO songs (path, filename, fingerprint, rating, times_played, deleted)
+            VALUES ('/gone/old.mp3', 'old.mp3', ?, 6, 9, 0)",
+            [&fingerprint],
+        )
+        .unwrap();
+        let old_id = t.last_insert_rowid();
+        let row = |path: &str| {
+            t.query_row(
+                "SELECT id, rating, times_played FROM songs WHERE path = ?",
+                [path],
+                |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, i32>(1)?,
+                        row.get::<_, i32>(2)?,
+                    ))
+                },
+            )
+            .unwrap()
+        };
+        let count = || {
+            t.query_row(
+                "SELECT count(*) FROM songs WHERE fingerprint = ?",
+                [&fingerprint],
+                |row| row.get::<_, u32>(0),
+            )
+            .unwrap()
+        };
+        let before = count();
+
+        // Die alte Datei fehlt: Die Zeile wandert samt Rating und Plays mit.
+        add_song_in_transaction(&moved, &t).unwrap();
+        assert_eq!(row(&moved.path), (old_id, 6, 9));
+        assert_eq!(count(), before);
+
+        // Die alte Datei gibt es noch: eine Kopie bekommt eine eigene Zeile.
+        add_song_in_transaction(&copy, &t).unwrap();
+        assert_eq!(row(&moved.path), (old_id, 6, 9));
+        assert_ne!(row(&copy.path).0, old_id);
+        assert_eq!(count(), before + 1);
+
+        t.rollback().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_unknown_length_stays_null() {
+        setup();
+        let path = env::temp_dir().join(format!("music-srv-broken-{}.mp3", std::process::id()));
+        std::fs::write(&path, b"not audio").unwrap();
+        let (mtime, size) = file_stamp_by_path(&path);
+        let song = probe_song(path.to_str().unwrap(), "broken.mp3", mtime, size);
+        let c = db_con().unwrap();
+        add_song_in_transaction(&song, &c).unwrap();
+
+        let seconds = db_select(
+            "SELECT seconds, duration_error FROM songs WHERE path = ?",
+            [path.to_str().unwrap()],
+            |row| {
+                Ok((
+                    row.get::<_, Option<u32>>(0)?,
+                    row.get::<_, Option<String>>(1)?,
+                ))
+            },
+        )
+        .unwrap();
+        c.execute("DELETE FROM songs WHERE path = ?", [path.to_str().unwrap()])
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(seconds.0, None);
+        assert!(seconds.1.is_some());
+    }
+
+    #[actix_web::test]
+    async fn test_skip_without_play() {
+        setup();
+        let app = web_test::init_service(App::new().service(net_skip)).await;
+        let req = web_test::TestRequest::get()
+            .uri("/skip/999999")
+            .to_request();
+        let res = web_test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_random_filter() {
+        setup();
+        let app = web_test::init_service(App::new().service(net_get_random_id)).await;
+        let random_id = |uri: &str| web_test::TestRequest::get().uri(uri).to_request();
+
+        // titanium ist 106 s lang, gardens 117 s.
+        for (uri, expected) in [
+            ("/random_id?max_seconds=110", "titanium-170190.mp3"),
+            (
+                "/random_id?min_seconds=110",
+                "gardens-stylish-chill-303261.mp3",
+            ),
+        ] {
+            let res = web_test::call_service(&app, random_id(uri)).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let body = web_test::read_body(res).await;
+            assert_eq!(body, song_id(expected).to_string());
+        }
+
+        for uri in [
+            "/random_id?artist=nonexistent",
+            "/random_id?folder=nonexistent",
+            "/random_id?min_seconds=200",
+        ] {
+            let res = web_test::call_service(&app, random_id(uri)).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_random_redirects() {
+        setup();
+        let app = web_test::init_service(App::new().service(net_song_random)).await;
+        let req = web_test::TestRequest::get()
+            .uri("/songs/random?x=1")
+            .to_request();
+        let res = web_test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::FOUND);
+        let location = res
+            .headers()
+            .get(header::LOCATION)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(location.starts_with("/songs/") && location.ends_with("?x=1"));
+    }
 
-    use crate::rng;
     #[test]
     fn test_vec_rng() {
         println!("test_vec_rng");
@@ -0,0 +1,429 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{
+    get,
+    http::{header, Method, StatusCode},
+    web::{self, Json},
+    HttpRequest, HttpResponse,
+};
+use color_eyre::eyre::Context;
+use lazy_static::lazy_static;
+use rusqlite::{named_params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    change_rating,
+    db::{db_con, db_uint32_read},
+    update_manager::db_update,
+    MyRes, GL_AUTO_RATING_FINISHES, GL_AUTO_RATING_SKIPS, GL_EARLY_SKIP_SECS,
+};
+
+const GL_HISTORY_DEFAULT_LIMIT: u32 = 50;
+const GL_HISTORY_MAX_LIMIT: u32 = 1000;
+
+// Kürzer als das wird ein Playback-Session-Fenster nie, auch wenn die Länge
+// des Songs unbekannt ist.
+const GL_MIN_SESSION_SECS: u64 = 30;
+
+lazy_static! {
+    // (Client, Song) -> Zeitpunkt, zu dem der Play gezählt wurde.
+    static ref PLAY_SESSIONS: Mutex<HashMap<(String, u32), Instant>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Deserialize)]
+struct ClientQuery {
+    client: Option<String>,
+}
+
+// Identifiziert einen Client. Mit ?client=... kann sich ein Gerät selbst benennen,
+// sonst zählen IP und User-Agent.
+pub fn client_key(req: &HttpRequest) -> String {
+    if let Some(client) = web::Query::<ClientQuery>::from_query(req.query_string())
+        .ok()
+        .and_then(|q| q.into_inner().client)
+        .filter(|c| !c.is_empty())
+    {
+        return client;
+    }
+    let ip = req
+        .peer_addr()
+        .map(|a| a.ip().to_string())
+        .unwrap_or_default();
+    let agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|a| a.to_str().ok())
+        .unwrap_or_default();
+    format!("{ip} {agent}")
+}
+
+// Nur ein GET ohne Range oder mit Range ab Byte 0 startet eine Wiedergabe. Alles
+// andere ist Spulen oder Nachladen innerhalb einer laufenden Wiedergabe. Ohne
+// Audio in der Antwort (z.B. 304 Not Modified) spielt auch nichts.
+fn is_playback_start(req: &HttpRequest, status: StatusCode) -> bool {
+    if req.method() != Method::GET
+        || !matches!(status, StatusCode::OK | StatusCode::PARTIAL_CONTENT)
+    {
+        return false;
+    }
+    match req.headers().get(header::RANGE) {
+        None => true,
+        Some(range) => range
+            .to_str()
+            .ok()
+            .and_then(|r| r.trim().strip_prefix("bytes="))
+            .is_some_and(|r| r.trim_start().starts_with("0-")),
+    }
+}
+
+// Zählt einen Play pro Playback-Session statt pro HTTP-Request. Eine Session
+// läuft so lange wie der Song; Requests desselben Clients für denselben Song
+// innerhalb dieser Zeit zählen nicht erneut.
+pub fn count_play(req: &HttpRequest, res: &HttpResponse, id: u32, source: &str) -> MyRes<bool> {
+    if !is_playback_start(req, res.status()) {
+        return Ok(false);
+    }
+
+    let seconds = db_uint32_read(&format!("SELECT seconds FROM songs WHERE id = {id}"))
+        .unwrap_or_default() as u64;
+    let window = Duration::from_secs(seconds.max(GL_MIN_SESSION_SECS));
+    let client = client_key(req);
+    let key = (client.clone(), id);
+    let now = Instant::now();
+
+    let mut sessions = PLAY_SESSIONS.lock().unwrap_or_else(|e| e.into_inner());
+    if sessions
+        .get(&key)
+        .is_some_and(|started| now.duration_since(*started) < window)
+    {
+        return Ok(false);
+    }
+    // Alte Sessions aufräumen, damit die Map nicht endlos wächst.
+    sessions.retain(|_, started| now.duration_since(*started) < Duration::from_secs(6 * 3600));
+    sessions.insert(key, now);
+    drop(sessions);
+
+    record_play(id, &client, source)?;
+    Ok(true)
+}
+
+// Zählt times_played hoch und schreibt den Play in die History.
+pub fn record_play(id: u32, client: &str, source: &str) -> MyRes<()> {
+    println!("record_play({id}, {client}, {source})");
+    let played_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+
+    let mut c = db_con()?;
+    let t = c.transaction().wrap_err("transaction")?;
+    t.execute(
+        "UPDATE songs SET times_played = times_played + 1 WHERE id = ?",
+        [id],
+    )?;
+    t.execute(
+        "INSERT INTO plays (song_id, played_at, client, source) VALUES (?, ?, ?, ?)",
+        (id, played_at, client, source),
+    )?;
+    t.commit().wrap_err("commit")?;
+    Ok(())
+}
+
+const GL_OUTCOME_EARLY_SKIP: &str = "skipped_early";
+const GL_OUTCOME_SKIP: &str = "skipped";
+const GL_OUTCOME_FINISHED: &str = "finished";
+
+#[derive(Serialize)]
+pub struct Play {
+    id: i64,
+    song_id: u32,
+    // Unix-Zeit in Sekunden.
+    played_at: i64,
+    client: String,
+    source: String,
+    // NULL, solange der Client nicht gemeldet hat, ob er übersprungen oder zu Ende gehört hat.
+    outcome: Option<String>,
+    songname: String,
+    artist: String,
+    album: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct HistoryQuery {
+    limit: Option<u32>,
+    client: Option<String>,
+    song: Option<u32>,
+    since: Option<i64>,
+    until: Option<i64>,
+}
+
+// Neueste Plays zuerst, optional gefiltert nach Client, Song und Zeitraum.
+#[get("/history")]
+async fn net_history(query: web::Query<HistoryQuery>) -> MyRes<Json<Vec<Play>>> {
+    println!("net_history({query:?})");
+    db_update()?;
+    let limit = query
+        .limit
+        .unwrap_or(GL_HISTORY_DEFAULT_LIMIT)
+        .min(GL_HISTORY_MAX_LIMIT);
+
+    let c = db_con()?;
+    let mut stmt = c.prepare(
+        "SELECT p.id, p.song_id, p.played_at, p.client, p.source, p.outcome, s.songname, s.artist, s.album
+        FROM plays p JOIN songs s ON s.id = p.song_id
+        WHERE (:client IS NULL OR p.client = :client)
+        AND (:song IS NULL OR p.song_id = :song)
+        AND (:since IS NULL OR p.played_at >= :since)
+        AND (:until IS NULL OR p.played_at < :until)
+        ORDER BY p.played_at DESC, p.id DESC
+        LIMIT :limit",
+    )?;
+    let plays = stmt
+        .query_map(
+            named_params! {
+                ":client": query.client,
+                ":song": query.song,
+                ":since": query.since,
+                ":until": query.until,
+                ":limit": limit,
+            },
+            |row| {
+                Ok(Play {
+                    id: row.get(0)?,
+                    song_id: row.get(1)?,
+                    played_at: row.get(2)?,
+                    client: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+                    source: row.get::<_, Option<String>>(4)?.unwrap_or_default(),
+                    outcome: row.get(5)?,
+                    songname: row.get::<_, Option<String>>(6)?.unwrap_or_default(),
+                    artist: row.get::<_, Option<String>>(7)?.unwrap_or_default(),
+                    album: row.get::<_, Option<String>>(8)?.unwrap_or_default(),
+                })
+            },
+        )?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Json(plays))
+}
+
+#[derive(Deserialize, Debug)]
+struct OutcomeQuery {
+    // Wiedergabeposition in Sekunden beim Skip. Ohne Angabe zählt die Zeit seit dem Play.
+    position: Option<u64>,
+}
+
+fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+enum Reported {
+    // Das neue Rating, wenn die Auto-Bewertung es geändert hat.
+    Recorded(Option<u32>),
+    // Für diesen Play wurde schon ein Ergebnis gemeldet.
+    Already(String),
+    // Der Client hat den Song nie abgespielt.
+    NoPlay(String),
+}
+
+// Schreibt das Ergebnis in den letzten Play des Clients für den Song. Danach wird,
+// falls eingeschaltet, das Rating automatisch angepasst. Jeder Play wird nur einmal
+// gewertet, ein wiederholter Request ändert nichts mehr.
+fn report_outcome(
+    req: &HttpRequest,
+    id: u32,
+    skipped: bool,
+    position: Option<u64>,
+) -> MyRes<Reported> {
+    let client = client_key(req);
+    let c = db_con()?;
+    let Some((play_id, played_at, reported)) = c
+        .query_row(
+            "SELECT id, played_at, outcome FROM plays WHERE song_id = ? AND client = ?
+            ORDER BY played_at DESC, id DESC LIMIT 1",
+            (id, &client),
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                ))
+            },
+        )
+        .optional()?
+    else {
+        return Ok(Reported::NoPlay(client));
+    };
+    if let Some(reported) = reported {
+        return Ok(Reported::Already(reported));
+    }
+
+    let outcome = if !skipped {
+        GL_OUTCOME_FINISHED
+    } else if position.unwrap_or((now_secs() - played_at).max(0) as u64) < *GL_EARLY_SKIP_SECS {
+        GL_OUTCOME_EARLY_SKIP
+    } else {
+        GL_OUTCOME_SKIP
+    };
+    println!("report_outcome({id}, {client}, {outcome})");
+    // Zwei gleichzeitige Meldungen: nur die erste zählt.
+    if c.execute(
+        "UPDATE plays SET outcome = ? WHERE id = ? AND outcome IS NULL",
+        (outcome, play_id),
+    )? == 0
+    {
+        return Ok(Reported::Already(outcome.to_string()));
+    }
+
+    // Über alle Clients: die letzten Ergebnisse des Songs, neuestes zuerst. Eins mehr
+    // als die Schwelle, damit eine längere Serie nicht erneut auslöst.
+    let window = GL_AUTO_RATING_SKIPS.max(*GL_AUTO_RATING_FINISHES);
+    if window == 0 {
+        return Ok(Reported::Recorded(None));
+    }
+    let window = window + 1;
+    let mut stmt = c.prepare(
+        "SELECT outcome FROM plays WHERE song_id = ? AND outcome IS NOT NULL
+        ORDER BY played_at DESC, id DESC LIMIT ?",
+    )?;
+    let outcomes = stmt
+        .query_map((id, window), |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    match auto_rating_delta(&outcomes, *GL_AUTO_RATING_SKIPS, *GL_AUTO_RATING_FINISHES) {
+        0 => Ok(Reported::Recorded(None)),
+        delta => Ok(Reported::Recorded(Some(change_rating(id, delta)?))),
+    }
+}
+
+// -1 nach `skips` frühen Skips in Folge, +1 nach `finishes` vollen Plays in Folge,
+// 0 schaltet die jeweilige Regel ab. Nur genau beim Erreichen der Schwelle, damit
+// nicht jeder weitere Skip das Rating erneut senkt.
+fn auto_rating_delta(outcomes: &[String], skips: u32, finishes: u32) -> i32 {
+    let Some(latest) = outcomes.first() else {
+        return 0;
+    };
+    let (threshold, delta) = match latest.as_str() {
+        GL_OUTCOME_EARLY_SKIP => (skips, -1),
+        GL_OUTCOME_FINISHED => (finishes, 1),
+        _ => return 0,
+    };
+    if threshold == 0 {
+        return 0;
+    }
+    let streak = outcomes.iter().take_while(|o| *o == latest).count() as u32;
+    if streak == threshold {
+        delta
+    } else {
+        0
+    }
+}
+
+#[get("/skip/{id}")]
+async fn net_skip(
+    req: HttpRequest,
+    id: web::Path<u32>,
+    query: web::Query<OutcomeQuery>,
+) -> MyRes<HttpResponse> {
+    println!("net_skip({id}, {query:?})");
+    db_update()?;
+    let id = id.into_inner();
+    Ok(outcome_response(
+        id,
+        "Skipped",
+        report_outcome(&req, id, true, query.position)?,
+    ))
+}
+
+#[get("/finished/{id}")]
+async fn net_finished(req: HttpRequest, id: web::Path<u32>) -> MyRes<HttpResponse> {
+    println!("net_finished({id})");
+    db_update()?;
+    let id = id.into_inner();
+    Ok(outcome_response(
+        id,
+        "Finished",
+        report_outcome(&req, id, false, None)?,
+    ))
+}
+
+fn outcome_response(id: u32, verb: &str, reported: Reported) -> HttpResponse {
+    match reported {
+        Reported::Recorded(Some(val)) => {
+            HttpResponse::Ok().body(format!("{verb} {id}. New Score: {val}"))
+        }
+        Reported::Recorded(None) => HttpResponse::Ok().body(format!("{verb} {id}.")),
+        Reported::Already(outcome) => HttpResponse::Conflict().body(format!(
+            "The last play of {id} was already reported as {outcome}."
+        )),
+        Reported::NoPlay(client) => {
+            HttpResponse::NotFound().body(format!("No play of song {id} for client {client}."))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header, StatusCode},
+        test::TestRequest,
+    };
+
+    use super::{
+        auto_rating_delta, is_playback_start, GL_OUTCOME_EARLY_SKIP, GL_OUTCOME_FINISHED,
+        GL_OUTCOME_SKIP,
+    };
+
+    #[test]
+    fn test_is_playback_start() {
+        let get = TestRequest::get().to_http_request();
+        assert!(is_playback_start(&get, StatusCode::OK));
+        // Revalidierung ohne Audio.
+        assert!(!is_playback_start(&get, StatusCode::NOT_MODIFIED));
+        assert!(!is_playback_start(&get, StatusCode::NOT_FOUND));
+
+        let range = |r: &str| {
+            TestRequest::get()
+                .insert_header((header::RANGE, r))
+                .to_http_request()
+        };
+        assert!(is_playback_start(
+            &range("bytes=0-"),
+            StatusCode::PARTIAL_CONTENT
+        ));
+        assert!(!is_playback_start(
+            &range("bytes=500-"),
+            StatusCode::PARTIAL_CONTENT
+        ));
+        assert!(!is_playback_start(
+            &TestRequest::default()
+                .method(actix_web::http::Method::HEAD)
+                .to_http_request(),
+            StatusCode::OK
+        ));
+    }
+
+    #[test]
+    fn test_auto_rating_delta() {
+        let o = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let e = GL_OUTCOME_EARLY_SKIP;
+        let f = GL_OUTCOME_FINISHED;
+
+        // Drei frühe Skips in Folge: runter, aber erst beim dritten.
+        assert_eq!(auto_rating_delta(&o(&[e, e, e]), 3, 5), -1);
+        assert_eq!(auto_rating_delta(&o(&[e, e]), 3, 5), 0);
+        // Ein späterer Skip oder ein voller Play unterbricht die Serie.
+        assert_eq!(auto_rating_delta(&o(&[e, e, GL_OUTCOME_SKIP, e]), 3, 5), 0);
+        assert_eq!(auto_rating_delta(&o(&[e, e, f]), 3, 5), 0);
+        assert_eq!(auto_rating_delta(&o(&[f, f, f, f, f]), 3, 5), 1);
+        assert_eq!(auto_rating_delta(&o(&[e, e, e, e]), 3, 5), 0);
+        // Abgeschaltet.
+        assert_eq!(auto_rating_delta(&o(&[e, e, e]), 0, 5), 0);
+        assert_eq!(auto_rating_delta(&o(&[GL_OUTCOME_SKIP]), 1, 1), 0);
+        assert_eq!(auto_rating_delta(&[], 1, 1), 0);
+    }
+}
@@ -0,0 +1,333 @@
+use std::{
+    collections::VecDeque,
+    io::Read,
+    process::{Command, Stdio},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use actix_web::{get, http::header, web::Bytes, HttpRequest, HttpResponse};
+use lazy_static::lazy_static;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    db::db_select,
+    get_songpath_by_id, get_weighted_random_id,
+    loudness::song_gain,
+    plays::record_play,
+    sessions::GL_RADIO_SESSION,
+    shuffle::{default_strategy, ShuffleQuery},
+    transcode::volume_filter,
+    MyRes, GL_FFMPEG, GL_RADIO_BITRATE, GL_RADIO_GAIN,
+};
+
+// Alle so viele Bytes Audio kommt bei ICY-Clients ein Metadaten-Block.
+const GL_ICY_METAINT: usize = 16000;
+// So viel Audio bekommt ein neuer Hörer sofort, damit der Player nicht leer anläuft.
+const GL_RADIO_BACKLOG_BYTES: usize = 128 * 1024;
+const GL_RADIO_CHANNEL_SIZE: usize = 256;
+// Schlagen so viele Songs hintereinander fehl, wird das Radio beendet.
+const GL_RADIO_MAX_FAILURES: u32 = 5;
+
+struct Chunk {
+    data: Bytes,
+    title: Arc<str>,
+}
+
+#[derive(Default)]
+struct RadioState {
+    // None, solange niemand zuhört. Wird der Sender verworfen, enden alle Streams.
+    sender: Option<broadcast::Sender<Arc<Chunk>>>,
+    backlog: VecDeque<Arc<Chunk>>,
+}
+
+lazy_static! {
+    static ref RADIO: Mutex<RadioState> = Mutex::new(RadioState::default());
+}
+
+fn radio_state() -> std::sync::MutexGuard<'static, RadioState> {
+    RADIO.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Meldet einen Hörer an und startet den Sender, falls er noch nicht läuft.
+fn subscribe() -> (VecDeque<Arc<Chunk>>, broadcast::Receiver<Arc<Chunk>>) {
+    let mut state = radio_state();
+    let rx = match &state.sender {
+        Some(sender) => sender.subscribe(),
+        None => {
+            let (sender, rx) = broadcast::channel(GL_RADIO_CHANNEL_SIZE);
+            state.sender = Some(sender);
+            thread::spawn(broadcast_loop);
+            rx
+        }
+    };
+    (state.backlog.clone(), rx)
+}
+
+// Verteilt einen Chunk an alle Hörer. false, wenn keiner mehr zuhört.
+fn publish(chunk: Chunk) -> bool {
+    let mut state = radio_state();
+    let listening = state
+        .sender
+        .as_ref()
+        .is_some_and(|s| s.receiver_count() > 0);
+    if !listening {
+        stop(&mut state);
+        return false;
+    }
+
+    let chunk = Arc::new(chunk);
+    state.backlog.push_back(chunk.clone());
+    let mut buffered = state.backlog.iter().map(|c| c.data.len()).sum::<usize>();
+    while buffered > GL_RADIO_BACKLOG_BYTES {
+        if let Some(old) = state.backlog.pop_front() {
+            buffered -= old.data.len();
+        }
+    }
+    if let Some(sender) = &state.sender {
+        sender.send(chunk).ok();
+    }
+    true
+}
+
+fn stop(state: &mut RadioState) {
+    println!("radio: stopped");
+    state.sender = None;
+    state.backlog.clear();
+}
+
+fn broadcast_loop() {
+    println!("radio: started");
+    let mut failures = 0;
+    loop {
+        match play_next() {
+            Ok(true) => failures = 0,
+            Ok(false) => return,
+            Err(e) => {
+                println!("radio: {e}");
+                failures += 1;
+                if failures >= GL_RADIO_MAX_FAILURES {
+                    stop(&mut radio_state());
+                    return;
+                }
+                thread::sleep(Duration::from_secs(1));
+            }
+        }
+    }
+}
+
+// Spielt einen Song in Echtzeit ins Radio. false, wenn keiner mehr zuhört.
+fn play_next() -> MyRes<bool> {
+    let id = get_weighted_random_id(
+        &default_strategy(),
+        &ShuffleQuery::default(),
+        GL_RADIO_SESSION,
+    )?
+    .ok_or("No songs to play.")?;
+    let path = get_songpath_by_id(id)?;
+    let (artist, songname) = db_select(
+        "SELECT artist, songname FROM songs WHERE id = ?",
+        [id],
+        |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+    )?;
+    let title: Arc<str> = match (artist.is_empty(), songname.is_empty()) {
+        (false, false) => format!("{artist} - {songname}"),
+        (true, false) => songname,
+        _ => path
+            .rsplit(['/', '\\'])
+            .next()
+            .unwrap_or_default()
+            .to_string(),
+    }
+    .into();
+    println!("radio: playing {id} ({title})");
+
+    // Alle Songs werden auf dasselbe Format gebracht, damit der Stream für den
+    // Player wie eine einzige Datei aussieht.
+    let mut ffmpeg = Command::new(&*GL_FFMPEG);
+    ffmpeg
+        .args([
+            "-nostdin",
+            "-hide_banner",
+            "-loglevel",
+            "error",
+            "-re",
+            "-i",
+        ])
+        .arg(&path)
+        .args(["-map", "0:a:0", "-vn", "-map_metadata", "-1"]);
+    if let Some(gain) = GL_RADIO_GAIN.and_then(|mode| song_gain(id, mode)) {
+        ffmpeg.arg("-af").arg(volume_filter(gain));
+    }
+    let mut ffmpeg = ffmpeg
+        .args(["-c:a", "libmp3lame", "-ar", "44100", "-ac", "2", "-b:a"])
+        .arg(format!("{}k", *GL_RADIO_BITRATE))
+        .args(["-id3v2_version", "0", "-write_xing", "0", "-f", "mp3", "-"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()?;
+    record_play(id, "radio", "radio")?;
+
+    let mut stdout = ffmpeg.stdout.take().ok_or("ffmpeg has no stdout")?;
+    let mut buf = [0u8; 8192];
+    let mut sent = 0;
+    loop {
+        let n = match stdout.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                ffmpeg.kill().ok();
+                ffmpeg.wait().ok();
+                return Err(e.into());
+            }
+        };
+        sent += n;
+        let chunk = Chunk {
+            data: Bytes::copy_from_slice(&buf[..n]),
+            title: title.clone(),
+        };
+        if !publish(chunk) {
+            ffmpeg.kill().ok();
+            ffmpeg.wait().ok();
+            return Ok(false);
+        }
+    }
+
+    let status = ffmpeg.wait()?;
+    if !status.success() || sent == 0 {
+        return Err(format!("ffmpeg failed for {path}: {status}").into());
+    }
+    Ok(true)
+}
+
+// Fügt alle GL_ICY_METAINT Bytes einen Metadaten-Block ein (Shoutcast-Protokoll).
+struct IcyWriter {
+    until_meta: usize,
+    last_title: Option<Arc<str>>,
+}
+
+impl IcyWriter {
+    fn new() -> Self {
+        IcyWriter {
+            until_meta: GL_ICY_METAINT,
+            last_title: None,
+        }
+    }
+
+    fn write(&mut self, chunk: &Chunk) -> Bytes {
+        let mut out = Vec::with_capacity(chunk.data.len() + 64);
+        let mut data = &chunk.data[..];
+        while !data.is_empty() {
+            let n = data.len().min(self.until_meta);
+            out.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            self.until_meta -= n;
+            if self.until_meta == 0 {
+                // Ein leerer Block (nur die 0) heißt "unverändert".
+                if self.last_title.as_ref() == Some(&chunk.title) {
+                    out.push(0);
+                } else {
+                    out.extend(icy_metadata(&chunk.title));
+                    self.last_title = Some(chunk.title.clone());
+                }
+                self.until_meta = GL_ICY_METAINT;
+            }
+        }
+        Bytes::from(out)
+    }
+}
+
+fn icy_metadata(title: &str) -> Vec<u8> {
+    let title = title.replace('\'', "’");
+    let mut text = format!("StreamTitle='{title}';").into_bytes();
+    // Die Länge wird in 16-Byte-Blöcken in einem Byte übertragen.
+    text.truncate(255 * 16);
+    let blocks = text.len().div_ceil(16);
+    text.resize(blocks * 16, 0);
+    let mut block = vec![blocks as u8];
+    block.extend(text);
+    block
+}
+
+struct Listener {
+    backlog: VecDeque<Arc<Chunk>>,
+    rx: broadcast::Receiver<Arc<Chunk>>,
+    icy: Option<IcyWriter>,
+}
+
+#[get("/radio")]
+async fn net_radio(req: HttpRequest) -> MyRes<HttpResponse> {
+    println!("net_radio");
+    let icy = req
+        .headers()
+        .get("icy-metadata")
+        .is_some_and(|v| v.as_bytes() == b"1");
+    let (backlog, rx) = subscribe();
+    let listener = Listener {
+        backlog,
+        rx,
+        icy: icy.then(IcyWriter::new),
+    };
+
+    let stream = futures_util::stream::unfold(listener, |mut l| async move {
+        let chunk = match l.backlog.pop_front() {
+            Some(chunk) => chunk,
+            None => loop {
+                match l.rx.recv().await {
+                    Ok(chunk) => break chunk,
+                    // Zu langsamer Hörer: verpasste Chunks werden übersprungen.
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            },
+        };
+        let data = match &mut l.icy {
+            Some(icy) => icy.write(&chunk),
+            None => chunk.data.clone(),
+        };
+        Some((Ok::<_, actix_web::Error>(data), l))
+    });
+
+    let mut res = HttpResponse::Ok();
+    res.content_type("audio/mpeg")
+        .insert_header((header::CACHE_CONTROL, "no-cache, no-store"))
+        .insert_header(("icy-name", "music-srv"))
+        .insert_header(("icy-br", GL_RADIO_BITRATE.to_string()));
+    if icy {
+        res.insert_header(("icy-metaint", GL_ICY_METAINT.to_string()));
+    }
+    Ok(res.streaming(stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_web::web::Bytes;
+
+    use super::{icy_metadata, Chunk, IcyWriter, GL_ICY_METAINT};
+
+    #[test]
+    fn test_icy_writer() {
+        let chunk = |len: usize, title: &str| Chunk {
+            data: Bytes::from(vec![1u8; len]),
+            title: Arc::from(title),
+        };
+        let meta = icy_metadata("A - B");
+        assert_eq!(meta[0], 2);
+        assert_eq!(&meta[1..21], b"StreamTitle='A - B';");
+        assert_eq!(meta.len(), 33);
+
+        let mut icy = IcyWriter::new();
+        let out = icy.write(&chunk(GL_ICY_METAINT + 10, "A - B"));
+        assert_eq!(out.len(), GL_ICY_METAINT + meta.len() + 10);
+        assert_eq!(&out[GL_ICY_METAINT..GL_ICY_METAINT + meta.len()], &meta[..]);
+
+        // Gleicher Titel: nur ein leerer Block.
+        let out = icy.write(&chunk(GL_ICY_METAINT, "A - B"));
+        assert_eq!(out.len(), GL_ICY_METAINT + 1);
+        assert_eq!(out[GL_ICY_METAINT - 10], 0);
+    }
+}
@@ -0,0 +1,90 @@
+use std::path::Path;
+
+use actix_files::NamedFile;
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::{
+        header::{self, HeaderMap, HttpDate},
+        StatusCode,
+    },
+    middleware::Next,
+    web, Error,
+};
+
+use crate::{
+    get_file_by_name, get_songpath_by_id, get_transcoded_file,
+    transcode::{cached_transcode, TranscodeQuery},
+};
+
+// actix-files ignoriert If-Range und antwortet auf "bytes=0-" mit 200. Beides
+// wird hier für /songs/{id} nachgezogen, damit Player zuverlässig spulen können.
+pub async fn song_ranges(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    if req.headers().contains_key(header::RANGE) && !if_range_matches(&req) {
+        // Die Datei hat sich geändert: komplette Datei statt Ausschnitt ausliefern.
+        req.headers_mut().remove(header::RANGE);
+    }
+
+    let mut res = next.call(req).await?;
+    if res.status() == StatusCode::OK && res.headers().contains_key(header::CONTENT_RANGE) {
+        *res.response_mut().status_mut() = StatusCode::PARTIAL_CONTENT;
+    }
+    Ok(res)
+}
+
+fn if_range_matches(req: &ServiceRequest) -> bool {
+    let Some(if_range) = req
+        .headers()
+        .get(header::IF_RANGE)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return true;
+    };
+    let Some(validators) = req
+        .match_info()
+        .get("id")
+        .and_then(|id| id.parse::<u32>().ok())
+        .and_then(|id| Some((id, get_songpath_by_id(id).ok()?)))
+        .and_then(|(id, path)| current_file(id, &path, req.query_string()))
+        .map(|file| file.into_response(req.request()).headers().clone())
+    else {
+        // Song oder Transkodierung gibt es (noch) nicht: Der Handler erzeugt eine
+        // neue Datei, ein alter Ausschnitt passt also sicher nicht.
+        return false;
+    };
+    validators_match(if_range, &validators)
+}
+
+// Die Datei, die der Handler für diesen Request ausliefern würde, ohne dafür
+// neu zu transkodieren.
+fn current_file(id: u32, path: &str, query: &str) -> Option<NamedFile> {
+    let query = web::Query::<TranscodeQuery>::from_query(query).ok()?;
+    match query.target(id).ok()? {
+        None => get_file_by_name(path).ok(),
+        Some(target) => {
+            let transcoded = cached_transcode(Path::new(path), &target)?;
+            get_transcoded_file(path, &transcoded).ok()
+        }
+    }
+}
+
+// If-Range enthält entweder einen starken ETag oder ein Datum (RFC 9110, 13.1.5).
+fn validators_match(if_range: &str, validators: &HeaderMap) -> bool {
+    let if_range = if_range.trim();
+    if if_range.starts_with('"') {
+        return validators
+            .get(header::ETAG)
+            .is_some_and(|etag| etag.as_bytes() == if_range.as_bytes());
+    }
+    if if_range.starts_with("W/") {
+        return false;
+    }
+    let modified = validators
+        .get(header::LAST_MODIFIED)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<HttpDate>().ok());
+    matches!((modified, if_range.parse::<HttpDate>()), (Some(m), Ok(d)) if m == d)
+}
@@ -0,0 +1,31 @@
+use actix_web::{
+    get,
+    web::{Data, Json},
+    HttpRequest, HttpResponse,
+};
+use minijinja::context;
+
+use crate::{
+    sessions::{session_songs, shuffle_session},
+    update_manager::db_update,
+    AppState, MyRes, Song,
+};
+
+// Die zuletzt zufällig ausgewählten Songs der eigenen Shuffle-Session, neuester zuerst.
+#[get("/recent")]
+async fn net_recent(req: HttpRequest) -> MyRes<Json<Vec<Song>>> {
+    println!("net_recent");
+    db_update()?;
+    let (session, _) = shuffle_session(&req);
+    Ok(Json(session_songs(&session)?))
+}
+
+#[get("/web/recent")]
+async fn net_recent_web(req: HttpRequest, app: Data<AppState>) -> MyRes<HttpResponse> {
+    println!("net_recent_web");
+    db_update()?;
+    let (session, _) = shuffle_session(&req);
+    let songs = session_songs(&session)?;
+    let rendered = app.render_template("recent.html", context! {songs => &songs})?;
+    Ok(HttpResponse::Ok().body(rendered))
+}
@@ -0,0 +1,355 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::Metadata,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use actix_web::{get, HttpResponse};
+use color_eyre::eyre::Context;
+use lazy_static::lazy_static;
+use rusqlite::Connection;
+use serde::Serialize;
+use walkdir::WalkDir;
+
+use crate::{
+    add_song_in_transaction, audio::is_audio_file, db::db_con, loudness::update_album_loudness,
+    probe_song, update_manager::db_update, MyRes, GL_DEBUG_SIZE, GL_MUSICDIR,
+};
+
+// Nach so vielen eingelesenen Dateien wird committet, damit andere Requests
+// während eines langen Scans nicht auf die Datenbank warten müssen.
+const GL_SCAN_BATCH_SIZE: usize = 500;
+
+lazy_static! {
+    // Der laufende bzw. zuletzt gelaufene Scan.
+    static ref SCAN_JOB: Mutex<Option<Arc<ScanJob>>> = Mutex::new(None);
+}
+
+static NEXT_JOB_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ScanState {
+    Running,
+    Finished,
+    Cancelled,
+    Failed,
+}
+
+pub struct ScanJob {
+    id: u64,
+    started: Instant,
+    cancel: AtomicBool,
+    seen: AtomicU64,
+    added: AtomicU64,
+    updated: AtomicU64,
+    removed: AtomicU64,
+    errors: AtomicU64,
+    // Endzustand, Fehlermeldung und Laufzeit, solange None läuft der Scan noch.
+    result: Mutex<Option<(ScanState, Option<String>, Duration)>>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ScanStatus {
+    id: u64,
+    state: ScanState,
+    error: Option<String>,
+    files_seen: u64,
+    files_added: u64,
+    files_updated: u64,
+    files_removed: u64,
+    errors: u64,
+    elapsed_secs: f64,
+}
+
+impl ScanJob {
+    fn new() -> Self {
+        ScanJob {
+            id: NEXT_JOB_ID.fetch_add(1, Ordering::Relaxed),
+            started: Instant::now(),
+            cancel: AtomicBool::new(false),
+            seen: AtomicU64::new(0),
+            added: AtomicU64::new(0),
+            updated: AtomicU64::new(0),
+            removed: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            result: Mutex::new(None),
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.result.lock().map(|r| r.is_none()).unwrap_or(false)
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancel.load(Ordering::Relaxed)
+    }
+
+    fn finish(&self, state: ScanState, error: Option<String>) {
+        if let Ok(mut r) = self.result.lock() {
+            *r = Some((state, error, self.started.elapsed()));
+        }
+    }
+
+    pub fn status(&self) -> ScanStatus {
+        let (state, error, elapsed) = match self.result.lock().ok().and_then(|r| r.clone()) {
+            Some(r) => r,
+            None => (ScanState::Running, None, self.started.elapsed()),
+        };
+        ScanStatus {
+            id: self.id,
+            state,
+            error,
+            files_seen: self.seen.load(Ordering::Relaxed),
+            files_added: self.added.load(Ordering::Relaxed),
+            files_updated: self.updated.load(Ordering::Relaxed),
+            files_removed: self.removed.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            elapsed_secs: elapsed.as_secs_f64(),
+        }
+    }
+}
+
+// Startet einen Scan im Hintergrund. Läuft schon einer, kommt dieser als Err zurück.
+pub fn start_scan() -> Result<Arc<ScanJob>, Arc<ScanJob>> {
+    let mut current = SCAN_JOB.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(job) = current.as_ref().filter(|j| j.is_running()) {
+        return Err(job.clone());
+    }
+
+    let job = Arc::new(ScanJob::new());
+    *current = Some(job.clone());
+    drop(current);
+
+    let j = job.clone();
+    thread::spawn(move || {
+        println!("scan job {} started", j.id);
+        // Auch eine Panic beim Einlesen muss den Job beenden, sonst gilt er ewig als
+        // laufend und /update antwortet nur noch mit 409.
+        let res = match panic::catch_unwind(AssertUnwindSafe(|| scan_library(&j))) {
+            Ok(res) => res.map_err(|e| e.to_string()),
+            Err(panic) => Err(panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .map_or("panic".to_string(), |s| format!("panic: {s}"))),
+        };
+        match res {
+            Ok(()) if j.is_cancelled() => j.finish(ScanState::Cancelled, None),
+            Ok(()) => j.finish(ScanState::Finished, None),
+            Err(e) => {
+                println!("scan job {} failed: {e}", j.id);
+                j.finish(ScanState::Failed, Some(e))
+            }
+        }
+        println!("scan job {}: {:?}", j.id, j.status());
+    });
+
+    Ok(job)
+}
+
+pub fn current_scan() -> Option<Arc<ScanJob>> {
+    SCAN_JOB.lock().ok().and_then(|j| j.clone())
+}
+
+#[get("/update")]
+async fn net_update_files() -> MyRes<HttpResponse> {
+    println!("net_update_files");
+    db_update()?;
+    Ok(match start_scan() {
+        Ok(job) => HttpResponse::Accepted().json(job.status()),
+        Err(running) => HttpResponse::Conflict().json(running.status()),
+    })
+}
+
+#[get("/update/status")]
+async fn net_update_status() -> MyRes<HttpResponse> {
+    println!("net_update_status");
+    Ok(match current_scan() {
+        Some(job) => HttpResponse::Ok().json(job.status()),
+        None => HttpResponse::NotFound().body("No scan has been started yet."),
+    })
+}
+
+#[get("/update/cancel")]
+async fn net_update_cancel() -> MyRes<HttpResponse> {
+    println!("net_update_cancel");
+    Ok(match current_scan().filter(|j| j.is_running()) {
+        Some(job) => {
+            job.cancel.store(true, Ordering::Relaxed);
+            HttpResponse::Accepted().json(job.status())
+        }
+        None => HttpResponse::NotFound().body("No scan is running."),
+    })
+}
+
+// Änderungszeitpunkt (Unix-Sekunden) und Größe einer Datei, daran wird erkannt,
+// ob eine Datei neu eingelesen werden muss.
+pub fn file_stamp(meta: &Metadata) -> (i64, i64) {
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+    (mtime, meta.len() as i64)
+}
+
+pub fn file_stamp_by_path(path: &Path) -> (i64, i64) {
+    path.metadata().map(|m| file_stamp(&m)).unwrap_or_default()
+}
+
+struct KnownFile {
+    mtime: Option<i64>,
+    size: Option<i64>,
+    deleted: bool,
+}
+
+struct PendingFile {
+    path: String,
+    filename: String,
+    mtime: i64,
+    size: i64,
+    is_new: bool,
+}
+
+fn scan_library(job: &ScanJob) -> MyRes<()> {
+    let mut size: u64 = 0;
+    let mut db = db_con()?;
+
+    let mut known = db
+        .prepare("SELECT path, mtime, size, deleted FROM songs")
+        .wrap_err("prepare known")?
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                KnownFile {
+                    mtime: row.get(1)?,
+                    size: row.get(2)?,
+                    deleted: row.get::<_, i32>(3)? != 0,
+                },
+            ))
+        })?
+        .collect::<Result<HashMap<_, _>, _>>()?;
+
+    let mut batch = Vec::with_capacity(GL_SCAN_BATCH_SIZE);
+
+    let files = WalkDir::new(&*GL_MUSICDIR);
+    for entry in files
+        .into_iter()
+        .filter_map(|e| {
+            e.inspect_err(|_| {
+                job.errors.fetch_add(1, Ordering::Relaxed);
+            })
+            .ok()
+        })
+        .filter(|f| f.file_type().is_file() && is_audio_file(f.path()))
+    {
+        if job.is_cancelled() {
+            break;
+        }
+        let Ok(meta) = entry.metadata() else {
+            job.errors.fetch_add(1, Ordering::Relaxed);
+            continue;
+        };
+        if GL_DEBUG_SIZE {
+            size += meta.len();
+        }
+        let (mtime, filesize) = file_stamp(&meta);
+        let path = entry.path().display().to_string();
+
+        // Was übrig bleibt, ist nicht mehr auf der Platte.
+        let is_new = match known.remove(&path) {
+            Some(k) if !k.deleted && k.mtime == Some(mtime) && k.size == Some(filesize) => None,
+            Some(_) => Some(false),
+            None => Some(true),
+        };
+        if let Some(is_new) = is_new {
+            batch.push(PendingFile {
+                path,
+                filename: entry.file_name().to_string_lossy().to_string(),
+                mtime,
+                size: filesize,
+                is_new,
+            });
+        }
+
+        let seen = job.seen.fetch_add(1, Ordering::Relaxed) + 1;
+        if seen.is_multiple_of(1000) {
+            println!("scan_library count: {seen}");
+        }
+
+        if batch.len() >= GL_SCAN_BATCH_SIZE {
+            write_batch(&mut db, &mut batch, job)?;
+        }
+    }
+    write_batch(&mut db, &mut batch, job)?;
+
+    // Bei einem abgebrochenen Scan wissen wir nicht, welche Dateien fehlen.
+    if !job.is_cancelled() {
+        let b = db.transaction().wrap_err("transaction")?;
+        let mut d = b
+            .prepare("UPDATE songs SET deleted = 1 WHERE path = ?")
+            .wrap_err("prepare delete")?;
+        for (path, _) in known.iter().filter(|(_, k)| !k.deleted) {
+            // Verschobene Dateien haben ihre Zeile schon mitgenommen.
+            if d.execute([path]).wrap_err("delete")? > 0 {
+                job.removed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        drop(d);
+        b.commit().wrap_err("commit")?;
+    }
+
+    if GL_DEBUG_SIZE {
+        println!("{size}");
+    }
+
+    Ok(())
+}
+
+// Liest die Dateien ohne Transaktion ein und schreibt danach nur die fertigen
+// Zeilen in einer kurzen Transaktion.
+fn write_batch(db: &mut Connection, batch: &mut Vec<PendingFile>, job: &ScanJob) -> MyRes<()> {
+    if batch.is_empty() {
+        return Ok(());
+    }
+    let mut probed = Vec::with_capacity(batch.len());
+    for f in batch.drain(..) {
+        if job.is_cancelled() {
+            break;
+        }
+        probed.push((probe_song(&f.path, &f.filename, f.mtime, f.size), f.is_new));
+    }
+
+    let b = db.transaction().wrap_err("transaction")?;
+    let mut albums = HashSet::new();
+    for (song, is_new) in &probed {
+        match add_song_in_transaction(song, &b) {
+            Err(e) => {
+                println!("scan_library: {} failed: {e}", song.path);
+                job.errors.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            Ok(album) => albums.extend(album),
+        }
+        if *is_new {
+            job.added.fetch_add(1, Ordering::Relaxed);
+        } else {
+            job.updated.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    // Jedes betroffene Album nur einmal pro Batch zusammenfassen.
+    for album in &albums {
+        update_album_loudness(&b, album)?;
+    }
+    b.commit().wrap_err("commit")?;
+    Ok(())
+}
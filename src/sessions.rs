@@ -0,0 +1,176 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::{
+    cookie::{time::Duration as CookieDuration, Cookie},
+    get,
+    web::{self, Json},
+    HttpRequest, HttpResponse,
+};
+use color_eyre::eyre::Context;
+use rand::{thread_rng, Rng};
+use rusqlite::OptionalExtension;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::{db_con, db_execute},
+    song_from_row,
+    update_manager::db_update,
+    MyRes, Song, GL_SONG_COLUMNS,
+};
+
+const GL_SESSION_COOKIE: &str = "shuffle_session";
+// So viele Picks werden pro Session aufbewahrt, mehr als jedes Replay-Fenster.
+const GL_SESSION_HISTORY: usize = 100;
+// Sessions ohne Pick seit so vielen Tagen werden gelöscht.
+const GL_SESSION_MAX_AGE_DAYS: i64 = 90;
+// Sessions des Servers selbst. Clients können sie weder per ?session= noch per
+// Cookie ansprechen.
+const GL_SERVER_SESSION_PREFIX: &str = "server:";
+pub const GL_RADIO_SESSION: &str = "server:radio";
+
+fn client_session(session: &str) -> bool {
+    !session.is_empty() && !session.starts_with(GL_SERVER_SESSION_PREFIX)
+}
+
+#[derive(Deserialize)]
+struct SessionQuery {
+    session: Option<String>,
+}
+
+// Die Shuffle-Session des Clients: ?session=... oder das Cookie. Hat der Client
+// keine, wird eine neue erzeugt und das Cookie muss mit der Antwort gesetzt werden.
+pub fn shuffle_session(req: &HttpRequest) -> (String, Option<Cookie<'static>>) {
+    if let Some(session) = web::Query::<SessionQuery>::from_query(req.query_string())
+        .ok()
+        .and_then(|q| q.into_inner().session)
+        .filter(|s| client_session(s))
+    {
+        return (session, None);
+    }
+    if let Some(cookie) = req.cookie(GL_SESSION_COOKIE) {
+        if client_session(cookie.value()) {
+            return (cookie.value().to_string(), None);
+        }
+    }
+    let session = format!("{:016x}", thread_rng().gen::<u64>());
+    let cookie = Cookie::build(GL_SESSION_COOKIE, session.clone())
+        .path("/")
+        .max_age(CookieDuration::days(GL_SESSION_MAX_AGE_DAYS))
+        .finish();
+    (session, Some(cookie))
+}
+
+// Die letzten Picks der Session, älteste zuerst.
+pub fn session_history(session: &str) -> MyRes<Vec<i32>> {
+    let c = db_con()?;
+    let mut stmt = c.prepare_cached(
+        "SELECT song_id FROM shuffle_history WHERE session = ? ORDER BY id DESC LIMIT ?",
+    )?;
+    let mut ids = stmt
+        .query_map((session, GL_SESSION_HISTORY), |row| row.get::<_, i32>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    ids.reverse();
+    Ok(ids)
+}
+
+pub fn push_history(session: &str, id: i32) -> MyRes<()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+
+    let mut c = db_con()?;
+    let t = c.transaction().wrap_err("transaction")?;
+    t.execute(
+        "INSERT INTO shuffle_history (session, song_id, picked_at) VALUES (?, ?, ?)",
+        (session, id, now),
+    )?;
+    t.execute(
+        "DELETE FROM shuffle_history WHERE session = ?1 AND id <= coalesce(
+            (SELECT id FROM shuffle_history WHERE session = ?1 ORDER BY id DESC LIMIT 1 OFFSET ?2), 0)",
+        (session, GL_SESSION_HISTORY),
+    )?;
+    t.execute(
+        "DELETE FROM shuffle_history WHERE picked_at < ?",
+        [now - GL_SESSION_MAX_AGE_DAYS * 86400],
+    )?;
+    t.commit().wrap_err("commit")?;
+    Ok(())
+}
+
+// Die Songs der Session, neuester zuerst.
+pub fn session_songs(session: &str) -> MyRes<Vec<Song>> {
+    let ids = session_history(session)?;
+    let c = db_con()?;
+    let mut stmt = c.prepare(&format!("select {GL_SONG_COLUMNS} from songs where id = ?"))?;
+    let mut songs = vec![];
+    for id in ids.iter().rev() {
+        // Inzwischen gelöschte Songs einfach auslassen.
+        if let Some(song) = stmt.query_row([id], song_from_row).optional()? {
+            songs.push(song);
+        }
+    }
+    Ok(songs)
+}
+
+#[derive(Serialize)]
+struct SessionInfo {
+    session: String,
+    songs: Vec<Song>,
+}
+
+#[get("/shuffle")]
+async fn net_shuffle_session(req: HttpRequest) -> MyRes<HttpResponse> {
+    println!("net_shuffle_session");
+    db_update()?;
+    let (session, cookie) = shuffle_session(&req);
+    let info = SessionInfo {
+        songs: session_songs(&session)?,
+        session,
+    };
+    let mut res = HttpResponse::Ok();
+    if let Some(cookie) = cookie {
+        res.cookie(cookie);
+    }
+    Ok(res.json(info))
+}
+
+#[get("/shuffle/reset")]
+async fn net_shuffle_reset(req: HttpRequest) -> MyRes<Json<SessionInfo>> {
+    println!("net_shuffle_reset");
+    db_update()?;
+    let (session, _) = shuffle_session(&req);
+    db_execute("DELETE FROM shuffle_history WHERE session = ?", [&session])?;
+    Ok(Json(SessionInfo {
+        session,
+        songs: vec![],
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{cookie::Cookie, test::TestRequest};
+
+    use super::{shuffle_session, GL_RADIO_SESSION, GL_SERVER_SESSION_PREFIX};
+
+    #[test]
+    fn test_shuffle_session() {
+        let req = TestRequest::get()
+            .uri("/random_id?session=abc")
+            .to_http_request();
+        assert_eq!(shuffle_session(&req), ("abc".to_string(), None));
+
+        // Die Session des Radios bekommt ein Client nie, er landet in einer neuen.
+        assert!(GL_RADIO_SESSION.starts_with(GL_SERVER_SESSION_PREFIX));
+        let req = TestRequest::get()
+            .uri(&format!("/shuffle/reset?session={GL_RADIO_SESSION}"))
+            .to_http_request();
+        let (session, cookie) = shuffle_session(&req);
+        assert_ne!(session, GL_RADIO_SESSION);
+        assert!(cookie.is_some());
+        let req = TestRequest::get()
+            .cookie(Cookie::new("shuffle_session", GL_RADIO_SESSION))
+            .to_http_request();
+        assert_ne!(shuffle_session(&req).0, GL_RADIO_SESSION);
+    }
+}
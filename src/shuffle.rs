@@ -0,0 +1,400 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::MAIN_SEPARATOR,
+};
+
+use serde::Deserialize;
+
+use crate::{rng, MyRes, GL_DEFAULT_RATING_SCALE, GL_MUSICDIR, GL_VOTE_CAP, GL_VOTE_FACTOR};
+
+// Gewichte werden damit skaliert, damit auch kleine Faktoren nach dem Runden wirken.
+const GL_WEIGHT_RESOLUTION: f32 = 100f32;
+// Ab so vielen Tagen ohne Play gibt es keinen zusätzlichen Bonus mehr.
+const GL_LEAST_RECENT_MAX_DAYS: f32 = 60f32;
+// Pro so vielen Tagen ohne Play steigt das Gewicht um das Einfache.
+const GL_LEAST_RECENT_DAYS_PER_STEP: f32 = 15f32;
+// Nie gespielte Songs bekommen bei "discovery" dieses Vielfache.
+const GL_DISCOVERY_BOOST: f32 = 5f32;
+
+// Was eine Strategie über einen Song wissen kann.
+#[derive(Debug, Clone, Default)]
+pub struct Candidate {
+    pub id: i32,
+    pub rating: u32,
+    pub vote: i32,
+    pub times_played: u32,
+    // Tage seit dem letzten Play laut plays-Tabelle, None wenn nie.
+    pub days_since_played: Option<f32>,
+    pub artist: String,
+    pub album: String,
+    // Album-Artist, sonst Artist. Zusammen mit album der Schlüssel eines Albums.
+    pub album_artist: String,
+}
+
+pub trait ShuffleStrategy {
+    // Relatives Gewicht für die Zufallsauswahl, 0 schließt den Song aus.
+    fn weight(&self, song: &Candidate) -> u32;
+}
+
+// Das Rating bestimmt das Gewicht exponentiell, die Votes verschieben es begrenzt.
+pub struct RatingPower {
+    pub scale: f32,
+}
+
+impl RatingPower {
+    fn base(&self, song: &Candidate) -> f32 {
+        let base = self.scale.powi(song.rating as i32 - 1).round();
+        let votes = song.vote.clamp(-*GL_VOTE_CAP, *GL_VOTE_CAP);
+        base * GL_WEIGHT_RESOLUTION * GL_VOTE_FACTOR.powi(votes)
+    }
+}
+
+impl ShuffleStrategy for RatingPower {
+    fn weight(&self, song: &Candidate) -> u32 {
+        self.base(song).round() as u32
+    }
+}
+
+// Wie RatingPower, aber lange nicht gespielte Songs werden bevorzugt.
+pub struct LeastRecentlyPlayed {
+    pub scale: f32,
+}
+
+impl ShuffleStrategy for LeastRecentlyPlayed {
+    fn weight(&self, song: &Candidate) -> u32 {
+        let days = song
+            .days_since_played
+            .unwrap_or(GL_LEAST_RECENT_MAX_DAYS)
+            .clamp(0.0, GL_LEAST_RECENT_MAX_DAYS);
+        let boost = 1.0 + days / GL_LEAST_RECENT_DAYS_PER_STEP;
+        (RatingPower { scale: self.scale }.base(song) * boost).round() as u32
+    }
+}
+
+// Wie RatingPower, aber noch nie gespielte Songs werden stark bevorzugt.
+pub struct Discovery {
+    pub scale: f32,
+}
+
+impl ShuffleStrategy for Discovery {
+    fn weight(&self, song: &Candidate) -> u32 {
+        let boost = if song.times_played == 0 {
+            GL_DISCOVERY_BOOST
+        } else {
+            1.0
+        };
+        (RatingPower { scale: self.scale }.base(song) * boost).round() as u32
+    }
+}
+
+// Jeder Song gleich wahrscheinlich.
+pub struct Uniform;
+
+impl ShuffleStrategy for Uniform {
+    fn weight(&self, _song: &Candidate) -> u32 {
+        1
+    }
+}
+
+// Wie viele der letzten Picks denselben Song, Artist oder dasselbe Album sperren.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReplayWindows {
+    pub songs: usize,
+    pub artists: usize,
+    pub albums: usize,
+}
+
+impl ReplayWindows {
+    // Lockert die Fenster schrittweise: erst Artist, dann Album, zuletzt der Song
+    // selbst. None, wenn nichts mehr zu lockern ist.
+    fn relax(self) -> Option<ReplayWindows> {
+        let mut w = self;
+        if w.artists > 0 {
+            w.artists /= 2;
+        } else if w.albums > 0 {
+            w.albums /= 2;
+        } else if w.songs > 0 {
+            w.songs /= 2;
+        } else {
+            return None;
+        }
+        Some(w)
+    }
+}
+
+// Zieht gewichtet einen Song, der weder selbst noch mit Artist oder Album in den
+// letzten Picks vorkam. history ist älteste zuerst. Gibt es keinen solchen Song,
+// werden die Fenster gelockert, statt endlos neu zu ziehen.
+pub fn pick_with_spread(
+    songs: &[(u32, Candidate)],
+    history: &[i32],
+    windows: ReplayWindows,
+) -> MyRes<i32> {
+    let by_id = songs
+        .iter()
+        .map(|(_, s)| (s.id, s))
+        .collect::<HashMap<_, _>>();
+    let mut windows = windows;
+    loop {
+        let recent = |n: usize| history.iter().rev().take(n);
+        let ids = recent(windows.songs).collect::<HashSet<_>>();
+        let artists = recent(windows.artists)
+            .filter_map(|id| by_id.get(id))
+            .map(|s| s.artist.as_str())
+            .filter(|a| !a.is_empty())
+            .collect::<HashSet<_>>();
+        let albums = recent(windows.albums)
+            .filter_map(|id| by_id.get(id))
+            .filter(|s| !s.album.is_empty())
+            .map(|s| (s.album.as_str(), s.album_artist.as_str()))
+            .collect::<HashSet<_>>();
+
+        let map = songs
+            .iter()
+            .filter(|(weight, s)| {
+                *weight > 0
+                    && !ids.contains(&s.id)
+                    && !artists.contains(s.artist.as_str())
+                    && !albums.contains(&(s.album.as_str(), s.album_artist.as_str()))
+            })
+            .map(|(weight, s)| (*weight, s.id))
+            .collect::<Vec<_>>();
+        if !map.is_empty() {
+            return rng(&map);
+        }
+        windows = match windows.relax() {
+            Some(w) => w,
+            None => return Err("No songs to choose from.".into()),
+        };
+        println!("pick_with_spread: relaxing to {windows:?}");
+    }
+}
+
+pub const GL_SHUFFLE_STRATEGIES: &[&str] = &["rating", "least_recent", "discovery", "uniform"];
+
+#[derive(Deserialize, Debug, Default)]
+pub struct ShuffleQuery {
+    pub strategy: Option<String>,
+    // Filter, gewichtet wird nur unter den passenden Songs.
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    // Relativ zu MUSICDIR, z.B. jazz/
+    pub folder: Option<String>,
+    pub min_rating: Option<u32>,
+    pub max_rating: Option<u32>,
+    pub min_seconds: Option<u32>,
+    pub max_seconds: Option<u32>,
+}
+
+impl ShuffleQuery {
+    // Pfad-Präfix für den folder-Filter, immer mit Trenner am Ende, damit jazz
+    // nicht auch jazzfunk trifft.
+    pub fn folder_prefix(&self) -> Option<String> {
+        let folder = self.folder.as_deref()?.trim_matches(['/', '\\']);
+        if folder.is_empty() {
+            return None;
+        }
+        let mut prefix = GL_MUSICDIR.join(folder).to_string_lossy().to_string();
+        prefix.push(MAIN_SEPARATOR);
+        Some(prefix)
+    }
+
+    // Ohne Angabe gilt "rating", wie bisher.
+    pub fn strategy(&self, scale: f32) -> Result<Box<dyn ShuffleStrategy>, String> {
+        Ok(match self.strategy.as_deref().unwrap_or("rating") {
+            "rating" => Box::new(RatingPower { scale }),
+            "least_recent" => Box::new(LeastRecentlyPlayed { scale }),
+            "discovery" => Box::new(Discovery { scale }),
+            "uniform" => Box::new(Uniform),
+            other => {
+                return Err(format!(
+                    "Unknown strategy {other}. Supported: {}",
+                    GL_SHUFFLE_STRATEGIES.join(", ")
+                ))
+            }
+        })
+    }
+}
+
+pub fn default_strategy() -> RatingPower {
+    RatingPower {
+        scale: GL_DEFAULT_RATING_SCALE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use std::path::MAIN_SEPARATOR;
+
+    use super::{
+        pick_with_spread, Candidate, Discovery, LeastRecentlyPlayed, RatingPower, ReplayWindows,
+        ShuffleQuery, ShuffleStrategy, Uniform,
+    };
+    use crate::{rng, GL_VOTE_CAP};
+
+    // Zieht n-mal und gibt den Anteil jedes Songs zurück.
+    fn shares(strategy: &dyn ShuffleStrategy, songs: &[Candidate], n: usize) -> HashMap<i32, f64> {
+        let map = songs
+            .iter()
+            .map(|s| (strategy.weight(s), s.id))
+            .collect::<Vec<_>>();
+        let mut hash = HashMap::<i32, usize>::new();
+        for _ in 0..n {
+            *hash.entry(rng(&map).unwrap_or_default()).or_default() += 1;
+        }
+        hash.into_iter()
+            .map(|(id, count)| (id, count as f64 / n as f64))
+            .collect()
+    }
+
+    fn assert_share(shares: &HashMap<i32, f64>, id: i32, expected: f64) {
+        let share = *shares.get(&id).unwrap_or(&0.0);
+        assert!(
+            (share - expected).abs() < 0.01,
+            "{id}: {share} statt {expected}"
+        );
+    }
+
+    fn song(id: i32, rating: u32) -> Candidate {
+        Candidate {
+            id,
+            rating,
+            times_played: 1,
+            days_since_played: Some(0.0),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_rating_power() {
+        let strategy = RatingPower { scale: 2.5 };
+        // Ohne Votes bleiben die Verhältnisse der Ratings wie bisher.
+        assert_eq!(strategy.weight(&song(1, 1)), 100);
+        assert_eq!(strategy.weight(&song(2, 2)), 300);
+        assert_eq!(strategy.weight(&song(3, 3)), 600);
+
+        let shares = shares(&strategy, &[song(1, 1), song(2, 2), song(3, 3)], 200_000);
+        assert_share(&shares, 1, 0.1);
+        assert_share(&shares, 2, 0.3);
+        assert_share(&shares, 3, 0.6);
+
+        let voted = |vote| Candidate { vote, ..song(1, 2) };
+        assert!(strategy.weight(&voted(1)) > 300);
+        assert!(strategy.weight(&voted(-1)) < 300);
+        assert!(strategy.weight(&voted(-1)) > 0);
+        // Votes über VOTE_CAP hinaus ändern nichts mehr.
+        assert_eq!(
+            strategy.weight(&voted(*GL_VOTE_CAP)),
+            strategy.weight(&voted(*GL_VOTE_CAP + 10))
+        );
+    }
+
+    #[test]
+    fn test_least_recently_played() {
+        let played = |id, days| Candidate {
+            days_since_played: days,
+            ..song(id, 2)
+        };
+        // Bonus 1x, 2x und für nie gespielte das Maximum 5x.
+        let songs = [played(1, Some(0.0)), played(2, Some(15.0)), played(3, None)];
+        let shares = shares(&LeastRecentlyPlayed { scale: 2.5 }, &songs, 200_000);
+        assert_share(&shares, 1, 1.0 / 8.0);
+        assert_share(&shares, 2, 2.0 / 8.0);
+        assert_share(&shares, 3, 5.0 / 8.0);
+    }
+
+    #[test]
+    fn test_discovery() {
+        let new = Candidate {
+            times_played: 0,
+            days_since_played: None,
+            ..song(2, 2)
+        };
+        let shares = shares(&Discovery { scale: 2.5 }, &[song(1, 2), new], 200_000);
+        assert_share(&shares, 1, 1.0 / 6.0);
+        assert_share(&shares, 2, 5.0 / 6.0);
+    }
+
+    #[test]
+    fn test_uniform() {
+        let songs = [song(1, 1), song(2, 4), song(3, 7), song(4, 2)];
+        let shares = shares(&Uniform, &songs, 200_000);
+        for id in 1..=4 {
+            assert_share(&shares, id, 0.25);
+        }
+    }
+
+    #[test]
+    fn test_shuffle_query() {
+        let query = |s: Option<&str>| ShuffleQuery {
+            strategy: s.map(String::from),
+            ..Default::default()
+        };
+        assert_eq!(query(None).strategy(2.5).unwrap().weight(&song(1, 2)), 300);
+        assert_eq!(
+            query(Some("uniform"))
+                .strategy(2.5)
+                .unwrap()
+                .weight(&song(1, 2)),
+            1
+        );
+        assert!(query(Some("shuffle")).strategy(2.5).is_err());
+
+        let folder = |f: &str| ShuffleQuery {
+            folder: Some(f.to_string()),
+            ..Default::default()
+        };
+        let jazz = folder("/jazz/").folder_prefix().unwrap();
+        assert_eq!(folder("jazz").folder_prefix().unwrap(), jazz);
+        assert!(jazz.ends_with(&format!("jazz{MAIN_SEPARATOR}")));
+        assert!(folder("/").folder_prefix().is_none());
+    }
+
+    #[test]
+    fn test_pick_with_spread() {
+        let tagged = |id, artist: &str, album: &str| {
+            (
+                100,
+                Candidate {
+                    artist: artist.to_string(),
+                    album: album.to_string(),
+                    album_artist: artist.to_string(),
+                    ..song(id, 2)
+                },
+            )
+        };
+        let songs = [
+            tagged(1, "A", "X"),
+            tagged(2, "A", "X"),
+            tagged(3, "B", "X"),
+            tagged(4, "C", "Z"),
+            tagged(5, "", ""),
+        ];
+        let windows = ReplayWindows {
+            songs: 2,
+            artists: 1,
+            albums: 2,
+        };
+        // Artist A und Album X (A) sind gesperrt, ebenso Song 1.
+        for _ in 0..1000 {
+            let id = pick_with_spread(&songs, &[1], windows).unwrap();
+            assert!([3, 4, 5].contains(&id), "{id}");
+        }
+        // Song 5 ohne Tags blockiert keine anderen Songs ohne Tags.
+        for _ in 0..1000 {
+            let id = pick_with_spread(&songs, &[4, 5], windows).unwrap();
+            assert!([1, 2, 3].contains(&id), "{id}");
+        }
+
+        // Zu kleine Bibliothek: erst Artist und Album lockern, den Song zuletzt.
+        let small = [tagged(1, "A", "X"), tagged(2, "A", "X")];
+        for _ in 0..1000 {
+            assert_eq!(pick_with_spread(&small, &[1], windows).unwrap(), 2);
+        }
+        assert_eq!(pick_with_spread(&small[..1], &[1], windows).unwrap(), 1);
+        assert!(pick_with_spread(&[], &[1], windows).is_err());
+    }
+}
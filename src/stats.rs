@@ -0,0 +1,275 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::{
+    get,
+    web::{self, Data, Json},
+    HttpResponse,
+};
+use minijinja::context;
+use rusqlite::{named_params, Connection};
+use serde::{Deserialize, Serialize};
+
+use crate::{db::db_con, update_manager::db_update, AppState, MyRes};
+
+const GL_STATS_DEFAULT_LIMIT: u32 = 10;
+const GL_STATS_MAX_LIMIT: u32 = 100;
+
+// Plays im Zeitraum, zusammen mit den Songdaten.
+const GL_PLAYS_IN_RANGE_SQL: &str = "FROM plays p JOIN songs s ON s.id = p.song_id
+    WHERE (:since IS NULL OR p.played_at >= :since)
+    AND (:until IS NULL OR p.played_at < :until)";
+
+#[derive(Deserialize, Debug)]
+struct StatsQuery {
+    // Unix-Zeit in Sekunden.
+    since: Option<i64>,
+    until: Option<i64>,
+    // Alternativ zu since: die letzten n Tage.
+    days: Option<i64>,
+    limit: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct TopSong {
+    id: u32,
+    songname: String,
+    artist: String,
+    album: String,
+    plays: u32,
+}
+
+#[derive(Serialize)]
+struct TopArtist {
+    artist: String,
+    plays: u32,
+}
+
+#[derive(Serialize)]
+struct TopAlbum {
+    album: String,
+    artist: String,
+    plays: u32,
+}
+
+#[derive(Serialize)]
+struct Day {
+    // YYYY-MM-DD in UTC.
+    day: String,
+    plays: u32,
+    seconds: u64,
+}
+
+#[derive(Serialize)]
+struct RatingCount {
+    rating: u32,
+    songs: u32,
+}
+
+#[derive(Serialize)]
+struct Library {
+    songs: u32,
+    total_seconds: u64,
+    never_played: u32,
+    // Anteil der Songs mit times_played = 0, zwischen 0 und 1.
+    never_played_share: f64,
+}
+
+#[derive(Serialize)]
+struct Stats {
+    since: Option<i64>,
+    until: Option<i64>,
+    plays: u32,
+    listening_seconds: u64,
+    top_songs: Vec<TopSong>,
+    top_artists: Vec<TopArtist>,
+    top_albums: Vec<TopAlbum>,
+    days: Vec<Day>,
+    ratings: Vec<RatingCount>,
+    library: Library,
+}
+
+fn get_stats(query: &StatsQuery) -> MyRes<Stats> {
+    let since = query.since.or_else(|| {
+        query.days.map(|days| {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or_default();
+            now - days * 86400
+        })
+    });
+    let until = query.until;
+    let limit = query
+        .limit
+        .unwrap_or(GL_STATS_DEFAULT_LIMIT)
+        .min(GL_STATS_MAX_LIMIT);
+
+    let c = db_con()?;
+    let days = get_days(&c, since, until)?;
+    Ok(Stats {
+        since,
+        until,
+        plays: days.iter().map(|d| d.plays).sum(),
+        listening_seconds: days.iter().map(|d| d.seconds).sum(),
+        top_songs: get_top_songs(&c, since, until, limit)?,
+        top_artists: get_top_artists(&c, since, until, limit)?,
+        top_albums: get_top_albums(&c, since, until, limit)?,
+        days,
+        ratings: get_ratings(&c)?,
+        library: get_library(&c)?,
+    })
+}
+
+fn get_top_songs(
+    c: &Connection,
+    since: Option<i64>,
+    until: Option<i64>,
+    limit: u32,
+) -> MyRes<Vec<TopSong>> {
+    let mut stmt = c.prepare(&format!(
+        "SELECT s.id, s.songname, s.artist, s.album, count(*) AS n {GL_PLAYS_IN_RANGE_SQL}
+        GROUP BY s.id ORDER BY n DESC, s.id LIMIT :limit"
+    ))?;
+    let songs = stmt
+        .query_map(
+            named_params! {":since": since, ":until": until, ":limit": limit},
+            |row| {
+                Ok(TopSong {
+                    id: row.get(0)?,
+                    songname: row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                    artist: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+                    album: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+                    plays: row.get(4)?,
+                })
+            },
+        )?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(songs)
+}
+
+fn get_top_artists(
+    c: &Connection,
+    since: Option<i64>,
+    until: Option<i64>,
+    limit: u32,
+) -> MyRes<Vec<TopArtist>> {
+    let mut stmt = c.prepare(&format!(
+        "SELECT s.artist, count(*) AS n {GL_PLAYS_IN_RANGE_SQL} AND s.artist != ''
+        GROUP BY s.artist ORDER BY n DESC, s.artist LIMIT :limit"
+    ))?;
+    let artists = stmt
+        .query_map(
+            named_params! {":since": since, ":until": until, ":limit": limit},
+            |row| {
+                Ok(TopArtist {
+                    artist: row.get(0)?,
+                    plays: row.get(1)?,
+                })
+            },
+        )?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(artists)
+}
+
+fn get_top_albums(
+    c: &Connection,
+    since: Option<i64>,
+    until: Option<i64>,
+    limit: u32,
+) -> MyRes<Vec<TopAlbum>> {
+    // Wie bei der Album-Lautheit: Album-Artist, sonst Artist.
+    let mut stmt = c.prepare(&format!(
+        "SELECT s.album, coalesce(nullif(s.album_artist, ''), s.artist) AS a, count(*) AS n
+        {GL_PLAYS_IN_RANGE_SQL} AND s.album != ''
+        GROUP BY s.album, a ORDER BY n DESC, s.album LIMIT :limit"
+    ))?;
+    let albums = stmt
+        .query_map(
+            named_params! {":since": since, ":until": until, ":limit": limit},
+            |row| {
+                Ok(TopAlbum {
+                    album: row.get(0)?,
+                    artist: row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                    plays: row.get(2)?,
+                })
+            },
+        )?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(albums)
+}
+
+// Hörzeit pro Tag. Gezählt wird die volle Songlänge, auch bei übersprungenen Songs.
+fn get_days(c: &Connection, since: Option<i64>, until: Option<i64>) -> MyRes<Vec<Day>> {
+    let mut stmt = c.prepare(&format!(
+        "SELECT date(p.played_at, 'unixepoch') AS day, count(*),
+        coalesce(sum(coalesce(s.duration_ms / 1000, s.seconds)), 0)
+        {GL_PLAYS_IN_RANGE_SQL}
+        GROUP BY day ORDER BY day"
+    ))?;
+    let days = stmt
+        .query_map(named_params! {":since": since, ":until": until}, |row| {
+            Ok(Day {
+                day: row.get(0)?,
+                plays: row.get(1)?,
+                seconds: row.get(2)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(days)
+}
+
+fn get_ratings(c: &Connection) -> MyRes<Vec<RatingCount>> {
+    let mut stmt = c.prepare(
+        "SELECT rating, count(*) FROM songs WHERE deleted = 0 AND merged_into IS NULL
+        GROUP BY rating ORDER BY rating",
+    )?;
+    let ratings = stmt
+        .query_map([], |row| {
+            Ok(RatingCount {
+                rating: row.get(0)?,
+                songs: row.get(1)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(ratings)
+}
+
+fn get_library(c: &Connection) -> MyRes<Library> {
+    let (songs, total_seconds, never_played) = c.query_row(
+        "SELECT count(*), coalesce(sum(coalesce(duration_ms / 1000, seconds)), 0),
+        count(*) FILTER (WHERE times_played = 0)
+        FROM songs WHERE deleted = 0 AND merged_into IS NULL",
+        [],
+        |row| Ok((row.get::<_, u32>(0)?, row.get(1)?, row.get::<_, u32>(2)?)),
+    )?;
+    Ok(Library {
+        songs,
+        total_seconds,
+        never_played,
+        never_played_share: if songs > 0 {
+            never_played as f64 / songs as f64
+        } else {
+            0.0
+        },
+    })
+}
+
+#[get("/stats")]
+async fn net_stats(query: web::Query<StatsQuery>) -> MyRes<Json<Stats>> {
+    println!("net_stats({query:?})");
+    db_update()?;
+    Ok(Json(get_stats(&query)?))
+}
+
+#[get("/web/stats")]
+async fn net_stats_web(app: Data<AppState>, query: web::Query<StatsQuery>) -> MyRes<HttpResponse> {
+    println!("net_stats_web({query:?})");
+    db_update()?;
+    let stats = get_stats(&query)?;
+    let max_day_seconds = stats.days.iter().map(|d| d.seconds).max().unwrap_or(0);
+    let rendered = app.render_template(
+        "stats.html",
+        context! {stats => &stats, days => query.days, max_day_seconds => max_day_seconds},
+    )?;
+    Ok(HttpResponse::Ok().body(rendered))
+}
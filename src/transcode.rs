@@ -0,0 +1,486 @@
+use std::{
+    fs::{self, FileTimes},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::SystemTime,
+};
+
+use actix_web::web::Bytes;
+use color_eyre::eyre::eyre;
+use serde::Deserialize;
+use tokio::sync::mpsc;
+
+use crate::{
+    loudness::{song_gain, GainMode},
+    scanner::file_stamp_by_path,
+    MyRes, GL_CACHEDIR, GL_FFMPEG, GL_TRANSCODE_CACHE_MB,
+};
+
+pub struct Codec {
+    pub name: &'static str,
+    pub extension: &'static str,
+    encoder: &'static str,
+    muxer: &'static str,
+    // Damit der Muxer in eine Pipe schreiben kann, ohne zurückzuspulen.
+    muxer_args: &'static [&'static str],
+    default_bitrate: u32,
+}
+
+const GL_CODECS: &[Codec] = &[
+    Codec {
+        name: "opus",
+        extension: "opus",
+        encoder: "libopus",
+        muxer: "ogg",
+        muxer_args: &[],
+        default_bitrate: 96,
+    },
+    Codec {
+        name: "mp3",
+        extension: "mp3",
+        encoder: "libmp3lame",
+        muxer: "mp3",
+        muxer_args: &["-write_xing", "0"],
+        default_bitrate: 128,
+    },
+    Codec {
+        name: "aac",
+        extension: "m4a",
+        encoder: "aac",
+        muxer: "ipod",
+        muxer_args: &["-movflags", "+frag_keyframe+empty_moov"],
+        default_bitrate: 128,
+    },
+    Codec {
+        name: "vorbis",
+        extension: "ogg",
+        encoder: "libvorbis",
+        muxer: "ogg",
+        muxer_args: &[],
+        default_bitrate: 128,
+    },
+];
+
+const GL_MIN_BITRATE: u32 = 32;
+const GL_MAX_BITRATE: u32 = 320;
+
+#[derive(Deserialize, Default)]
+pub struct TranscodeQuery {
+    pub format: Option<String>,
+    pub bitrate: Option<u32>,
+    // track oder album: ReplayGain beim Transkodieren anwenden.
+    pub gain: Option<String>,
+}
+
+pub struct Transcode {
+    pub codec: &'static Codec,
+    pub bitrate: u32,
+    // Verstärkung in dB.
+    pub gain: Option<f64>,
+}
+
+impl TranscodeQuery {
+    // Ohne format (oder mit format=original) wird die Originaldatei ausgeliefert.
+    pub fn target(&self, id: u32) -> MyRes<Option<Transcode>> {
+        let Some(format) = self.format.as_deref().filter(|f| *f != "original") else {
+            return Ok(None);
+        };
+        let codec = GL_CODECS
+            .iter()
+            .find(|c| c.name == format)
+            .ok_or_else(|| eyre!("Unknown format {format}"))?;
+        let bitrate = self
+            .bitrate
+            .unwrap_or(codec.default_bitrate)
+            .clamp(GL_MIN_BITRATE, GL_MAX_BITRATE);
+        let gain = match self.gain.as_deref() {
+            None | Some("off") => None,
+            Some(mode) => {
+                let mode = GainMode::parse(mode).ok_or_else(|| eyre!("Unknown gain {mode}"))?;
+                song_gain(id, mode)
+            }
+        };
+        Ok(Some(Transcode {
+            codec,
+            bitrate,
+            gain,
+        }))
+    }
+}
+
+pub fn supported_formats() -> Vec<&'static str> {
+    GL_CODECS.iter().map(|c| c.name).collect()
+}
+
+fn transcode_dir() -> PathBuf {
+    GL_CACHEDIR.join("transcode")
+}
+
+// Ändert sich die Quelldatei, ändert sich auch der Name im Cache.
+fn cache_file(source: &Path, variant: &str, extension: &str) -> PathBuf {
+    let (mtime, size) = file_stamp_by_path(source);
+    let key = format!("{}|{mtime}|{size}|{variant}", source.display());
+    let hash = blake3::hash(key.as_bytes()).to_hex();
+    transcode_dir().join(format!("{}.{extension}", &hash[..32]))
+}
+
+fn transcode_file(source: &Path, target: &Transcode) -> PathBuf {
+    let variant = format!(
+        "{}|{}|{:.2}",
+        target.codec.name,
+        target.bitrate,
+        target.gain.unwrap_or_default()
+    );
+    cache_file(source, &variant, target.codec.extension)
+}
+
+pub fn cached_transcode(source: &Path, target: &Transcode) -> Option<PathBuf> {
+    let file = transcode_file(source, target);
+    file.exists().then(|| {
+        touch(&file);
+        file
+    })
+}
+
+// Transkodiert nach stdout und schreibt dabei in eine tmp-Datei, die erst nach
+// erfolgreichem Ende von ffmpeg in den Cache wandert. Der erste Chunk kommt, sobald
+// ffmpeg ihn liefert. Bricht der Client ab, läuft die Transkodierung für den Cache weiter.
+pub fn stream_transcoded(source: &Path, target: &Transcode) -> MyRes<TranscodeStream> {
+    println!(
+        "stream_transcoded({}, {} {}k)",
+        source.display(),
+        target.codec.name,
+        target.bitrate
+    );
+    let file = transcode_file(source, target);
+    fs::create_dir_all(transcode_dir())?;
+    let tmp = tmp_file(&file);
+    let cache = fs::File::create(&tmp)?;
+
+    let mut ffmpeg = Command::new(&*GL_FFMPEG);
+    ffmpeg
+        .args(["-nostdin", "-hide_banner", "-loglevel", "error", "-i"])
+        .arg(source)
+        .args([
+            "-map",
+            "0:a:0",
+            "-vn",
+            "-map_metadata",
+            "0",
+            "-c:a",
+            target.codec.encoder,
+            "-b:a",
+        ])
+        .arg(format!("{}k", target.bitrate));
+    if let Some(gain) = target.gain {
+        ffmpeg.arg("-af").arg(volume_filter(gain));
+    }
+    let ffmpeg = ffmpeg
+        .args(target.codec.muxer_args)
+        .args(["-f", target.codec.muxer, "-"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn();
+    let ffmpeg = match ffmpeg {
+        Ok(c) => c,
+        Err(e) => {
+            remove(&tmp);
+            return Err(eyre!("Could not start {}: {e}", *GL_FFMPEG).into());
+        }
+    };
+
+    let (tx, rx) = mpsc::channel(16);
+    let source = source.to_owned();
+    thread::spawn(move || {
+        if let Err(e) = tee_transcoded(ffmpeg, cache, &tmp, &file, &tx) {
+            println!("stream_transcoded({}): {e}", source.display());
+            remove(&tmp);
+            tx.blocking_send(Err(e.to_string())).ok();
+        }
+    });
+    Ok(rx)
+}
+
+pub type TranscodeStream = mpsc::Receiver<Result<Bytes, String>>;
+
+fn tee_transcoded(
+    mut ffmpeg: Child,
+    mut cache: fs::File,
+    tmp: &Path,
+    file: &Path,
+    tx: &mpsc::Sender<Result<Bytes, String>>,
+) -> MyRes<()> {
+    let mut stdout = ffmpeg.stdout.take().ok_or("ffmpeg has no stdout")?;
+    let mut buf = [0u8; 65536];
+    let mut client = true;
+    let mut written = 0;
+    loop {
+        let n = match stdout.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                ffmpeg.kill().ok();
+                ffmpeg.wait().ok();
+                return Err(e.into());
+            }
+        };
+        if let Err(e) = cache.write_all(&buf[..n]) {
+            ffmpeg.kill().ok();
+            ffmpeg.wait().ok();
+            return Err(e.into());
+        }
+        written += n;
+        if client
+            && tx
+                .blocking_send(Ok(Bytes::copy_from_slice(&buf[..n])))
+                .is_err()
+        {
+            client = false;
+        }
+    }
+
+    let status = ffmpeg.wait()?;
+    if !status.success() || written == 0 {
+        return Err(eyre!("ffmpeg failed: {status}").into());
+    }
+    drop(cache);
+    finish_cached(tmp, file)
+}
+
+// Alle Segmente eines Songs für HLS als MPEG-TS mit AAC, erzeugt in einem einzigen
+// Durchlauf von ffmpeg. Getrennt kodierte Stücke knacken an den Übergängen, weil jeder
+// AAC-Encoder mit Stille anfängt. Gibt den Ordner mit 0.ts, 1.ts, ... zurück.
+pub fn get_segments(source: &Path, bitrate: u32, segment_secs: u64) -> MyRes<PathBuf> {
+    println!("get_segments({}, {bitrate}k)", source.display());
+    let variant = format!("hls|{bitrate}|{segment_secs}");
+    let dir = cache_file(source, &variant, "hls");
+
+    let output = [
+        "-map".to_string(),
+        "0:a:0".to_string(),
+        "-vn".to_string(),
+        "-map_metadata".to_string(),
+        "-1".to_string(),
+        "-c:a".to_string(),
+        "aac".to_string(),
+        "-b:a".to_string(),
+        format!("{bitrate}k"),
+        "-f".to_string(),
+        "segment".to_string(),
+        "-segment_time".to_string(),
+        segment_secs.to_string(),
+        "-segment_format".to_string(),
+        "mpegts".to_string(),
+    ];
+    run_cached(source, dir, &[], &output, Some("%d.ts"))
+}
+
+pub fn volume_filter(gain: f64) -> String {
+    format!("volume={gain:.2}dB")
+}
+
+// atime dient als "zuletzt benutzt" für die Verdrängung. Die mtime bleibt, sonst
+// ändern sich ETag und Last-Modified bei jedem Abruf.
+fn touch(path: &Path) {
+    if let Ok(f) = fs::File::options()
+        .append(path.is_file())
+        .read(path.is_dir())
+        .open(path)
+    {
+        f.set_times(FileTimes::new().set_accessed(SystemTime::now()))
+            .ok();
+    }
+}
+
+fn remove(path: &Path) {
+    if path.is_dir() {
+        fs::remove_dir_all(path).ok();
+    } else {
+        fs::remove_file(path).ok();
+    }
+}
+
+// Mit pattern ist file ein Ordner, in den ffmpeg mehrere Dateien schreibt.
+fn run_cached(
+    source: &Path,
+    file: PathBuf,
+    input: &[String],
+    output: &[String],
+    pattern: Option<&str>,
+) -> MyRes<PathBuf> {
+    if file.exists() {
+        touch(&file);
+        return Ok(file);
+    }
+
+    fs::create_dir_all(transcode_dir())?;
+    let tmp = tmp_file(&file);
+    let target = match pattern {
+        Some(pattern) => {
+            fs::create_dir_all(&tmp)?;
+            tmp.join(pattern)
+        }
+        None => tmp.clone(),
+    };
+
+    let result = Command::new(&*GL_FFMPEG)
+        .args(["-nostdin", "-hide_banner", "-loglevel", "error", "-y"])
+        .args(input)
+        .arg("-i")
+        .arg(source)
+        .args(output)
+        .arg(&target)
+        .output();
+    let output = match result {
+        Ok(o) => o,
+        Err(e) => {
+            remove(&tmp);
+            return Err(eyre!("Could not start {}: {e}", *GL_FFMPEG).into());
+        }
+    };
+    if !output.status.success() {
+        remove(&tmp);
+        return Err(eyre!(
+            "ffmpeg failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    finish_cached(&tmp, &file)?;
+    Ok(file)
+}
+
+// Parallele Requests für dieselbe Datei schreiben in eigene Dateien.
+fn tmp_file(file: &Path) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    file.with_extension(format!(
+        "{}.{}.tmp",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+fn finish_cached(tmp: &Path, file: &Path) -> MyRes<()> {
+    if let Err(e) = fs::rename(tmp, file) {
+        remove(tmp);
+        // Ein paralleler Request war schneller, dessen Ergebnis ist genauso gut.
+        if !file.exists() {
+            return Err(e.into());
+        }
+    }
+
+    evict(&transcode_dir(), *GL_TRANSCODE_CACHE_MB * 1024 * 1024, file);
+    Ok(())
+}
+
+fn dir_size(dir: &Path) -> u64 {
+    fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .filter_map(|e| e.metadata().ok())
+                .map(|m| m.len())
+                .sum()
+        })
+        .unwrap_or_default()
+}
+
+// Löscht die am längsten nicht benutzten Dateien und HLS-Ordner, bis der Cache
+// wieder unter max_bytes liegt. keep wird nie gelöscht.
+fn evict(dir: &Path, max_bytes: u64, keep: &Path) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut files = entries
+        .filter_map(Result::ok)
+        .filter_map(|e| {
+            let meta = e.metadata().ok()?;
+            let used = meta.accessed().ok()?;
+            let len = if meta.is_dir() {
+                dir_size(&e.path())
+            } else {
+                meta.len()
+            };
+            Some((used, len, e.path()))
+        })
+        .filter(|(_, _, p)| p.extension().is_some_and(|e| e != "tmp"))
+        .collect::<Vec<_>>();
+
+    let mut total = files.iter().map(|(_, len, _)| len).sum::<u64>();
+    files.sort();
+    for (_, len, path) in files {
+        if total <= max_bytes {
+            break;
+        }
+        if path == keep {
+            continue;
+        }
+        remove(&path);
+        if !path.exists() {
+            total -= len;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, FileTimes},
+        time::Duration,
+    };
+
+    use super::{evict, TranscodeQuery};
+
+    #[test]
+    fn test_evict() {
+        let dir = std::env::temp_dir().join(format!("music-srv-evict-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let now = std::time::SystemTime::now();
+        for (i, name) in ["a.opus", "b.opus", "c.hls", "d.opus"].iter().enumerate() {
+            let path = dir.join(name);
+            // HLS-Segmente liegen in einem Ordner und zählen zusammen.
+            let f = if name.ends_with(".hls") {
+                fs::create_dir(&path).unwrap();
+                fs::write(path.join("0.ts"), vec![0u8; 50]).unwrap();
+                fs::write(path.join("1.ts"), vec![0u8; 50]).unwrap();
+                fs::File::open(&path).unwrap()
+            } else {
+                fs::write(&path, vec![0u8; 100]).unwrap();
+                fs::File::options().append(true).open(&path).unwrap()
+            };
+            f.set_times(FileTimes::new().set_accessed(now - Duration::from_secs(100 - i as u64)))
+                .unwrap();
+        }
+
+        // a ist am ältesten, soll aber behalten werden.
+        evict(&dir, 250, &dir.join("a.opus"));
+        let mut left = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(left, vec!["a.opus", "d.opus"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_transcode_query() {
+        let query = |format: Option<&str>, bitrate| TranscodeQuery {
+            format: format.map(str::to_string),
+            bitrate,
+            gain: None,
+        };
+        assert!(query(None, Some(96)).target(1).unwrap().is_none());
+        assert!(query(Some("original"), None).target(1).unwrap().is_none());
+        assert!(query(Some("flac"), None).target(1).is_err());
+
+        let t = query(Some("opus"), None).target(1).unwrap().unwrap();
+        assert_eq!((t.codec.name, t.bitrate), ("opus", 96));
+        let t = query(Some("mp3"), Some(1000)).target(1).unwrap().unwrap();
+        assert_eq!((t.codec.name, t.bitrate), ("mp3", 320));
+    }
+}
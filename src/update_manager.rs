@@ -28,7 +28,21 @@ pub fn db_update() -> MyRes<()> {
             match version.as_str() {
                 "2" => v2()?,
                 "3" => v3()?,
-                "4" => break,
+                "4" => v4()?,
+                "5" => v5()?,
+                "6" => v6()?,
+                "7" => v7()?,
+                "8" => v8()?,
+                "9" => v9()?,
+                "10" => v10()?,
+                "11" => v11()?,
+                "12" => v12()?,
+                "13" => v13()?,
+                "14" => v14()?,
+                "15" => v15()?,
+                "16" => v16()?,
+                "17" => v17()?,
+                "18" => break,
                 _ => Err(eyre!("Unbekannte Versionsnummer!"))?,
             }
         }
@@ -93,3 +107,170 @@ fn v3() -> MyRes<()> {
     db_execute("UPDATE songs SET rating = 7 WHERE rating >= 6400", [])?;
     db_execute("UPDATE config SET value = '4' WHERE key LIKE 'version'", [])
 }
+
+fn v4() -> MyRes<()> {
+    db_execute("ALTER TABLE songs ADD COLUMN mtime INTEGER", [])?;
+    db_execute("ALTER TABLE songs ADD COLUMN size INTEGER", [])?;
+    db_execute("UPDATE config SET value = '5' WHERE key LIKE 'version'", [])
+}
+
+fn v5() -> MyRes<()> {
+    db_execute("ALTER TABLE songs ADD COLUMN fingerprint TEXT", [])?;
+    db_execute(
+        "CREATE INDEX IF NOT EXISTS songs_fingerprint ON songs (fingerprint)",
+        [],
+    )?;
+    // Alles einmal neu einlesen, damit die Fingerprints gefüllt werden.
+    db_execute("UPDATE songs SET mtime = NULL", [])?;
+    db_execute("UPDATE config SET value = '6' WHERE key LIKE 'version'", [])
+}
+
+fn v6() -> MyRes<()> {
+    db_execute("ALTER TABLE songs ADD COLUMN merged_into INTEGER", [])?;
+    db_execute("UPDATE config SET value = '7' WHERE key LIKE 'version'", [])
+}
+
+fn v7() -> MyRes<()> {
+    db_execute("ALTER TABLE songs ADD COLUMN album_artist TEXT", [])?;
+    db_execute("ALTER TABLE songs ADD COLUMN track_number INTEGER", [])?;
+    db_execute("ALTER TABLE songs ADD COLUMN disc_number INTEGER", [])?;
+    db_execute("ALTER TABLE songs ADD COLUMN year INTEGER", [])?;
+    db_execute("ALTER TABLE songs ADD COLUMN genre TEXT", [])?;
+    db_execute("ALTER TABLE songs ADD COLUMN composer TEXT", [])?;
+    db_execute("ALTER TABLE songs ADD COLUMN comment TEXT", [])?;
+    // Tags beim nächsten Scan neu einlesen.
+    db_execute("UPDATE songs SET mtime = NULL", [])?;
+    db_execute("UPDATE config SET value = '8' WHERE key LIKE 'version'", [])
+}
+
+fn v8() -> MyRes<()> {
+    db_execute("ALTER TABLE songs ADD COLUMN metadata_source TEXT", [])?;
+    db_execute("UPDATE songs SET mtime = NULL", [])?;
+    db_execute("UPDATE config SET value = '9' WHERE key LIKE 'version'", [])
+}
+
+fn v9() -> MyRes<()> {
+    db_execute(
+        "ALTER TABLE songs ADD COLUMN tags_locked INTEGER DEFAULT 0 NOT NULL",
+        [],
+    )?;
+    db_execute(
+        "UPDATE config SET value = '10' WHERE key LIKE 'version'",
+        [],
+    )
+}
+
+fn v10() -> MyRes<()> {
+    db_execute("ALTER TABLE songs ADD COLUMN cover_key TEXT", [])?;
+    db_execute("UPDATE songs SET mtime = NULL", [])?;
+    db_execute(
+        "UPDATE config SET value = '11' WHERE key LIKE 'version'",
+        [],
+    )
+}
+
+fn v11() -> MyRes<()> {
+    db_execute("ALTER TABLE songs ADD COLUMN track_loudness REAL", [])?;
+    db_execute("ALTER TABLE songs ADD COLUMN track_peak REAL", [])?;
+    db_execute("ALTER TABLE songs ADD COLUMN album_loudness REAL", [])?;
+    db_execute("ALTER TABLE songs ADD COLUMN album_peak REAL", [])?;
+    // Lautheit beim nächsten Scan messen.
+    db_execute("UPDATE songs SET mtime = NULL", [])?;
+    db_execute(
+        "UPDATE config SET value = '12' WHERE key LIKE 'version'",
+        [],
+    )
+}
+
+fn v12() -> MyRes<()> {
+    // NULL, wenn die Länge nicht bestimmt werden konnte, der Grund steht dann in duration_error.
+    db_execute("ALTER TABLE songs ADD COLUMN duration_ms INTEGER", [])?;
+    db_execute("ALTER TABLE songs ADD COLUMN duration_error TEXT", [])?;
+    db_execute("UPDATE songs SET mtime = NULL", [])?;
+    db_execute(
+        "UPDATE config SET value = '13' WHERE key LIKE 'version'",
+        [],
+    )
+}
+
+fn v13() -> MyRes<()> {
+    db_execute(
+        "CREATE TABLE plays (
+        id INTEGER not null primary key autoincrement,
+        song_id INTEGER not null,
+        played_at INTEGER not null,
+        client TEXT,
+        source TEXT,
+        outcome TEXT
+    );",
+        [],
+    )?;
+    db_execute("CREATE INDEX plays_played_at ON plays (played_at)", [])?;
+    db_execute("CREATE INDEX plays_song_id ON plays (song_id)", [])?;
+    db_execute(
+        "UPDATE config SET value = '14' WHERE key LIKE 'version'",
+        [],
+    )
+}
+
+fn v14() -> MyRes<()> {
+    db_execute(
+        "CREATE TABLE shuffle_history (
+        id INTEGER not null primary key autoincrement,
+        session TEXT not null,
+        song_id INTEGER not null,
+        picked_at INTEGER not null
+    );",
+        [],
+    )?;
+    db_execute(
+        "CREATE INDEX shuffle_history_session ON shuffle_history (session, id)",
+        [],
+    )?;
+    db_execute(
+        "CREATE INDEX shuffle_history_picked_at ON shuffle_history (picked_at)",
+        [],
+    )?;
+    db_execute(
+        "UPDATE config SET value = '15' WHERE key LIKE 'version'",
+        [],
+    )
+}
+
+fn v15() -> MyRes<()> {
+    // Für die Album-Lautheit, die Songs eines Albums werden darüber gesucht.
+    db_execute(
+        "CREATE INDEX songs_album ON songs (album, album_artist, artist)",
+        [],
+    )?;
+    db_execute(
+        "UPDATE config SET value = '16' WHERE key LIKE 'version'",
+        [],
+    )
+}
+
+fn v16() -> MyRes<()> {
+    // NULL, wenn die Lautheit gemessen werden konnte, sonst der Grund.
+    db_execute("ALTER TABLE songs ADD COLUMN loudness_error TEXT", [])?;
+    // Songs ohne Lautheit beim nächsten Scan neu einlesen, z.B. Opus über ffmpeg.
+    db_execute(
+        "UPDATE songs SET mtime = NULL WHERE track_loudness IS NULL AND deleted = 0",
+        [],
+    )?;
+    db_execute(
+        "UPDATE config SET value = '17' WHERE key LIKE 'version'",
+        [],
+    )
+}
+
+fn v17() -> MyRes<()> {
+    // Unbekannte Länge war bisher 0 Sekunden.
+    db_execute(
+        "UPDATE songs SET seconds = NULL WHERE duration_ms IS NULL",
+        [],
+    )?;
+    db_execute(
+        "UPDATE config SET value = '18' WHERE key LIKE 'version'",
+        [],
+    )
+}
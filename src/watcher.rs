@@ -0,0 +1,151 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, RecvTimeoutError},
+    thread,
+    time::Duration,
+};
+
+use color_eyre::eyre::Context;
+use notify::RecursiveMode;
+use notify_debouncer_full::{new_debouncer, DebounceEventResult};
+use rusqlite::{Connection, OptionalExtension};
+use walkdir::WalkDir;
+
+use crate::{
+    add_song_in_transaction, audio::is_audio_file, db::db_con, loudness::update_album_loudness,
+    probe_song, scanner::file_stamp, update_manager::db_update, MyRes, ProbedSong, GL_MUSICDIR,
+    GL_WATCH_DEBOUNCE_SECS,
+};
+
+// Startet den Watcher in einem eigenen Thread. Events werden gesammelt, bis
+// GL_WATCH_DEBOUNCE_SECS lang Ruhe ist, und dann in einer Transaktion angewendet.
+pub fn start_watcher() {
+    thread::spawn(|| {
+        if let Err(e) = run_watcher() {
+            println!("watcher stopped: {e}");
+        }
+    });
+}
+
+fn run_watcher() -> MyRes<()> {
+    let debounce = Duration::from_secs(*GL_WATCH_DEBOUNCE_SECS);
+    let (tx, rx) = channel::<DebounceEventResult>();
+    let mut debouncer = new_debouncer(debounce, None, tx)?;
+    debouncer.watch(&*GL_MUSICDIR, RecursiveMode::Recursive)?;
+    println!("watching {}", GL_MUSICDIR.display());
+
+    let mut pending = BTreeSet::new();
+    loop {
+        let res = if pending.is_empty() {
+            rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        } else {
+            rx.recv_timeout(debounce)
+        };
+        match res {
+            Ok(Ok(events)) => {
+                for event in events {
+                    pending.extend(event.paths.iter().cloned());
+                }
+            }
+            Ok(Err(errors)) => {
+                for e in errors {
+                    println!("watcher error: {e}");
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                let paths = std::mem::take(&mut pending);
+                if let Err(e) = sync_paths(&paths) {
+                    println!("watcher: sync of {} paths failed: {e}", paths.len());
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    Ok(())
+}
+
+// Gleicht die betroffenen Pfade mit der songs-Tabelle ab: Vorhandene Dateien
+// werden (neu) eingelesen, verschwundene Dateien und Ordner als gelöscht markiert.
+fn sync_paths(paths: &BTreeSet<PathBuf>) -> MyRes<()> {
+    println!("sync_paths({} paths)", paths.len());
+    db_update()?;
+
+    // Erst alles einlesen, die Transaktion hält die Datenbank dann nur kurz.
+    let mut db = db_con()?;
+    let mut probed = vec![];
+    let mut gone = vec![];
+    for path in paths {
+        if path.is_dir() {
+            for entry in WalkDir::new(path)
+                .into_iter()
+                .filter_map(Result::ok)
+                .filter(|f| f.file_type().is_file())
+            {
+                match probe_file(entry.path(), &db) {
+                    Ok(song) => probed.extend(song),
+                    Err(e) => println!("sync_paths: {} failed: {e}", entry.path().display()),
+                }
+            }
+        } else if path.is_file() {
+            match probe_file(path, &db) {
+                Ok(song) => probed.extend(song),
+                Err(e) => println!("sync_paths: {} failed: {e}", path.display()),
+            }
+        } else {
+            gone.push(path.display().to_string());
+        }
+    }
+
+    let b = db.transaction().wrap_err("transaction")?;
+    let mut albums = HashSet::new();
+    for song in &probed {
+        match add_song_in_transaction(song, &b) {
+            Ok(album) => albums.extend(album),
+            Err(e) => println!("sync_paths: {} failed: {e}", song.path),
+        }
+    }
+    for album in &albums {
+        update_album_loudness(&b, album)?;
+    }
+    for p in gone {
+        b.execute(
+            "UPDATE songs SET deleted = 1
+            WHERE deleted = 0 AND (path = ?1 OR substr(path, 1, length(?2)) = ?2)",
+            (&p, format!("{p}{}", std::path::MAIN_SEPARATOR)),
+        )
+        .wrap_err("delete")?;
+    }
+    b.commit().wrap_err("commit")?;
+    Ok(())
+}
+
+// None, wenn die Datei keine Audiodatei ist oder sich nicht geändert hat.
+fn probe_file(path: &Path, c: &Connection) -> MyRes<Option<ProbedSong>> {
+    if !is_audio_file(path) {
+        return Ok(None);
+    }
+    let (mtime, size) = file_stamp(&path.metadata()?);
+    let p = path.display().to_string();
+
+    let known = c
+        .query_row(
+            "SELECT mtime, size, deleted FROM songs WHERE path = ?",
+            [&p],
+            |row| {
+                Ok((
+                    row.get::<_, Option<i64>>(0)?,
+                    row.get::<_, Option<i64>>(1)?,
+                    row.get::<_, i32>(2)?,
+                ))
+            },
+        )
+        .optional()?;
+    if known == Some((Some(mtime), Some(size), 0)) {
+        return Ok(None);
+    }
+
+    let filename = path.file_name().unwrap_or_default().to_string_lossy();
+    Ok(Some(probe_song(&p, &filename, mtime, size)))
+}